@@ -4,8 +4,10 @@ mod setup;
 mod utils;
 
 use crate::setup::setup;
-use crate::utils::{bob_balance, join_native_pool, mine_block, spawn_miner, upgrade_miner};
-use candid::Principal;
+use crate::utils::{
+    bob_balance, get_miner_status, join_native_pool, mine_block, spawn_miner, upgrade_miner,
+};
+use candid::{Nat, Principal};
 
 // System canister IDs
 
@@ -55,6 +57,20 @@ fn test_spawn_upgrade_miner() {
     assert_eq!(bob_balance(&pic, user_id), 240_000_000_000_u64);
 }
 
+#[test]
+fn test_miner_status_is_restricted_to_the_owner() {
+    let user_id = Principal::from_slice(&[0xFF; 29]);
+    let other_user_id = Principal::from_slice(&[0xFE; 29]);
+    let pic = setup(vec![user_id]);
+
+    let miner_id = spawn_miner(&pic, user_id, 100_000_000);
+
+    let status = get_miner_status(&pic, user_id, miner_id).unwrap();
+    assert!(status.cycles > Nat::from(0_u8));
+    assert!(status.module_hash.is_some());
+    assert!(get_miner_status(&pic, other_user_id, miner_id).is_err());
+}
+
 #[test]
 fn test_native_pool() {
     let user_1 = Principal::from_slice(&[0xFF; 29]);
@@ -1,6 +1,7 @@
 use crate::{
     BOB_CANISTER_ID, BOB_LEDGER_CANISTER_ID, NNS_ICP_INDEX_CANISTER_ID, NNS_ICP_LEDGER_CANISTER_ID,
 };
+use bob_minter_v2::miner::MinerStatus;
 use bob_minter_v2::Stats;
 use candid::{Nat, Principal};
 use ic_ledger_core::block::BlockType;
@@ -88,6 +89,22 @@ pub(crate) fn upgrade_miner(pic: &PocketIc, user_id: Principal, miner_id: Princi
     .unwrap()
 }
 
+pub(crate) fn get_miner_status(
+    pic: &PocketIc,
+    user_id: Principal,
+    miner_id: Principal,
+) -> Result<MinerStatus, String> {
+    update_candid_as::<_, (Result<MinerStatus, String>,)>(
+        pic,
+        BOB_CANISTER_ID,
+        user_id,
+        "get_miner_status",
+        (miner_id,),
+    )
+    .unwrap()
+    .0
+}
+
 pub(crate) fn join_native_pool(pic: &PocketIc, user_id: Principal, amount: u64) {
     let block_index = transfer(pic, user_id, amount);
 
@@ -0,0 +1,86 @@
+use crate::memory::{get_miner_owner, is_admin, is_denied, push_audit_entry};
+use crate::vesting::VestingParams;
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+/// Parts of the minter that can be paused independently.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, CandidType,
+)]
+pub enum Subsystem {
+    Lottery,
+    Payouts,
+    Spawning,
+    PoolJoins,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub enum AdminAction {
+    AddAdmin(Principal),
+    RemoveAdmin(Principal),
+    Pause(Subsystem),
+    Resume(Subsystem),
+    SetPoolPayoutConcurrency(u64),
+    Deny {
+        principal: Principal,
+        reason: String,
+    },
+    Allow(Principal),
+    RegisterRewardStream(u64),
+    StopRewardStream(u64),
+    SetVestingParams(VestingParams),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub struct AuditEntry {
+    pub timestamp: u64,
+    pub caller: Principal,
+    pub action: AdminAction,
+}
+
+/// A miner or owner excluded from the lottery.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub struct DenyListEntry {
+    pub principal: Principal,
+    pub reason: String,
+    pub added_at: u64,
+    pub added_by: Principal,
+}
+
+/// Returns true if the miner or its owner is on the deny list.
+pub fn is_denied_miner(miner: Principal) -> bool {
+    is_denied(miner) || get_miner_owner(miner).is_some_and(is_denied)
+}
+
+pub fn is_controller(caller: &Principal) -> bool {
+    ic_cdk::api::is_controller(caller)
+}
+
+pub fn is_controller_or_admin(caller: &Principal) -> bool {
+    is_controller(caller) || is_admin(*caller)
+}
+
+pub fn ensure_controller() -> Result<Principal, String> {
+    let caller = ic_cdk::caller();
+    if !is_controller(&caller) {
+        return Err("only controllers can call this endpoint".to_string());
+    }
+    Ok(caller)
+}
+
+pub fn ensure_controller_or_admin() -> Result<Principal, String> {
+    let caller = ic_cdk::caller();
+    if !is_controller_or_admin(&caller) {
+        return Err("only controllers and admins can call this endpoint".to_string());
+    }
+    Ok(caller)
+}
+
+/// Appends an admin action to the audit log in stable memory.
+pub fn record_admin_action(caller: Principal, action: AdminAction) {
+    push_audit_entry(AuditEntry {
+        timestamp: ic_cdk::api::time(),
+        caller,
+        action,
+    });
+}
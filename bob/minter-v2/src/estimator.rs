@@ -0,0 +1,144 @@
+//! Estimates of what users get for their ICP before they pay, based on the
+//! ICP/XDR conversion rate of the cycles minting canister. The rate is
+//! cached so that the query can answer without a call.
+
+use crate::payment::CYCLES_FOR_CREATION;
+use crate::runtime::Runtime;
+use crate::{
+    mutate_state, read_state, E8S_PER_POOL_DAY, MAINNET_CYCLE_MINTER_CANISTER_ID, SEC_NANOS,
+};
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+
+/// Cycles burned per round by a miner with the default settings, see the
+/// miner canister.
+pub const MINER_DEFAULT_CYCLES_PER_ROUND: u64 = 10_000_000_001;
+/// The rate is fetched again once the cached one is older than this.
+const RATE_CACHE_NANOS: u64 = 10 * 60 * SEC_NANOS;
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct IcpXdrConversionRate {
+    pub timestamp_seconds: u64,
+    pub xdr_permyriad_per_icp: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct IcpXdrConversionRateResponse {
+    data: IcpXdrConversionRate,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct CachedConversionRate {
+    pub rate: IcpXdrConversionRate,
+    pub fetched_at: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct CostEstimate {
+    pub amount_e8s: u64,
+    pub xdr_permyriad_per_icp: u64,
+    pub rate_timestamp_seconds: u64,
+    /// Cycles minted by the cycles minting canister for the amount.
+    pub expected_cycles: u64,
+    /// Cycles given to a spawned miner, whatever the amount paid.
+    pub cycles_for_creation: u64,
+    /// Rounds a new miner can burn at the default rate before it needs a
+    /// top up.
+    pub miner_runway_rounds: u64,
+    /// Days in the pool bought by the amount.
+    pub pool_days: u64,
+}
+
+pub async fn fetch_icp_xdr_conversion_rate() -> Result<IcpXdrConversionRate, String> {
+    let result: Result<(IcpXdrConversionRateResponse,), _> = ic_cdk::api::call::call(
+        MAINNET_CYCLE_MINTER_CANISTER_ID,
+        "get_icp_xdr_conversion_rate",
+        (),
+    )
+    .await;
+    result
+        .map(|(response,)| response.data)
+        .map_err(|(code, msg)| {
+            format!("failed to fetch the ICP/XDR conversion rate ({code:?}): {msg}")
+        })
+}
+
+/// Returns the cached conversion rate, fetching it if it is missing or
+/// stale.
+pub async fn refresh_conversion_rate<R: Runtime>(
+    runtime: &R,
+) -> Result<IcpXdrConversionRate, String> {
+    let now = runtime.time();
+    if let Some(cached) = read_state(|s| s.conversion_rate.clone()) {
+        if now < cached.fetched_at + RATE_CACHE_NANOS {
+            return Ok(cached.rate);
+        }
+    }
+    let rate = runtime.icp_xdr_conversion_rate().await?;
+    mutate_state(|s| {
+        s.conversion_rate = Some(CachedConversionRate {
+            rate: rate.clone(),
+            fetched_at: now,
+        })
+    });
+    Ok(rate)
+}
+
+pub fn estimate(amount_e8s: u64, rate: &IcpXdrConversionRate) -> CostEstimate {
+    // One XDR is worth 10^12 cycles and the rate is in 10^-4 XDR per ICP,
+    // so one e8 is worth `xdr_permyriad_per_icp` cycles.
+    let expected_cycles =
+        (amount_e8s as u128 * rate.xdr_permyriad_per_icp as u128).min(u64::MAX as u128) as u64;
+    CostEstimate {
+        amount_e8s,
+        xdr_permyriad_per_icp: rate.xdr_permyriad_per_icp,
+        rate_timestamp_seconds: rate.timestamp_seconds,
+        expected_cycles,
+        cycles_for_creation: CYCLES_FOR_CREATION,
+        miner_runway_rounds: CYCLES_FOR_CREATION / MINER_DEFAULT_CYCLES_PER_ROUND,
+        pool_days: amount_e8s / E8S_PER_POOL_DAY,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replace_state;
+    use crate::runtime::mock::MockRuntime;
+    use crate::State;
+    use futures::executor::block_on;
+
+    #[test]
+    fn should_convert_icp_to_cycles() {
+        let rate = IcpXdrConversionRate {
+            timestamp_seconds: 0,
+            xdr_permyriad_per_icp: 50_000,
+        };
+        let estimate = estimate(300_000_000, &rate);
+        assert_eq!(estimate.expected_cycles, 15_000_000_000_000);
+        assert_eq!(estimate.pool_days, 3);
+        assert_eq!(estimate.miner_runway_rounds, 249);
+    }
+
+    #[test]
+    fn should_cache_the_conversion_rate() {
+        replace_state(State::new());
+        let runtime = MockRuntime::new(0);
+        runtime.xdr_permyriad_per_icp.set(50_000);
+
+        assert_eq!(
+            block_on(refresh_conversion_rate(&runtime)).map(|r| r.xdr_permyriad_per_icp),
+            Ok(50_000)
+        );
+        runtime.xdr_permyriad_per_icp.set(60_000);
+        assert_eq!(
+            block_on(refresh_conversion_rate(&runtime)).map(|r| r.xdr_permyriad_per_icp),
+            Ok(50_000)
+        );
+        runtime.time.set(RATE_CACHE_NANOS);
+        assert_eq!(
+            block_on(refresh_conversion_rate(&runtime)).map(|r| r.xdr_permyriad_per_icp),
+            Ok(60_000)
+        );
+    }
+}
@@ -0,0 +1,208 @@
+//! Append-only log of every state-changing event of the minter. The stable
+//! structures are updated alongside the log, the heap `State` is rebuilt on
+//! upgrade by replaying the events of the round in progress.
+
+use crate::memory::{event_count, get_event, push_event};
+use crate::State;
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub enum EventType {
+    MinerSpawned {
+        miner: Principal,
+        owner: Principal,
+        block_index: u64,
+    },
+    /// A membership was bought or renewed, `block_index` is the ICP ledger
+    /// block that paid for it.
+    PoolJoined {
+        owner: Principal,
+        block_index: u64,
+        amount_e8s: u64,
+        expiration: u64,
+    },
+    MinerSpawnedWithCycles {
+        miner: Principal,
+        owner: Principal,
+        cycles: u64,
+    },
+    PoolJoinedWithCycles {
+        owner: Principal,
+        cycles: u64,
+        expiration: u64,
+    },
+    CyclesSubmitted {
+        miner: Principal,
+        cycles: u64,
+    },
+    /// The block is identified by the timestamp of the event.
+    BlockSolved {
+        miner: Principal,
+        to: Principal,
+        rewards: u64,
+        total_cycles_burned: u64,
+        miner_cycles_burned: u64,
+    },
+    RewardPaid {
+        block_timestamp: u64,
+        to: Principal,
+        amount: u64,
+        ledger_index: u64,
+    },
+    MinerUpgraded {
+        miner: Principal,
+    },
+    /// Rewards added to the claimable balance of `to` instead of being
+    /// transferred.
+    RewardCredited {
+        block_timestamp: u64,
+        to: Principal,
+        amount: u64,
+    },
+    RewardsClaimed {
+        owner: Principal,
+        amount: u64,
+        ledger_index: u64,
+    },
+    /// Part of a reward held in a vesting schedule of `to`.
+    RewardVested {
+        block_timestamp: u64,
+        to: Principal,
+        amount: u64,
+    },
+    VestedReleased {
+        owner: Principal,
+        amount: u64,
+        ledger_index: u64,
+    },
+    BobLocked {
+        owner: Principal,
+        amount_e8s: u64,
+        unlock_at: u64,
+        multiplier_bps: u64,
+        ledger_index: u64,
+    },
+    /// `amount_e8s` is the amount returned, net of the transfer fee.
+    BobUnlocked {
+        owner: Principal,
+        amount_e8s: u64,
+        ledger_index: u64,
+    },
+    /// A secondary reward whose transfers kept failing, see `streams`.
+    ExtraRewardDropped {
+        block_timestamp: u64,
+        to: Principal,
+        ledger_id: Principal,
+        amount: u64,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub struct Event {
+    pub timestamp: u64,
+    pub payload: EventType,
+}
+
+pub fn record_event(payload: EventType, timestamp: u64) {
+    push_event(Event { timestamp, payload });
+}
+
+/// Applies an event to the heap state.
+pub fn apply_event(state: &mut State, event: &Event) {
+    match &event.payload {
+        EventType::CyclesSubmitted { miner, cycles } => {
+            state
+                .miner_to_burned_cycles
+                .entry(*miner)
+                .and_modify(|e| *e = e.saturating_add(*cycles))
+                .or_insert(*cycles);
+        }
+        EventType::BlockSolved { .. } => {
+            state.miner_to_burned_cycles.clear();
+        }
+        // Recorded in the stable structures.
+        EventType::MinerSpawned { .. }
+        | EventType::MinerSpawnedWithCycles { .. }
+        | EventType::PoolJoined { .. }
+        | EventType::PoolJoinedWithCycles { .. }
+        | EventType::RewardPaid { .. }
+        | EventType::MinerUpgraded { .. }
+        | EventType::RewardCredited { .. }
+        | EventType::RewardsClaimed { .. }
+        | EventType::RewardVested { .. }
+        | EventType::VestedReleased { .. }
+        | EventType::BobLocked { .. }
+        | EventType::BobUnlocked { .. }
+        | EventType::ExtraRewardDropped { .. } => {}
+    }
+}
+
+pub fn replay(events: impl IntoIterator<Item = Event>) -> State {
+    let mut state = State::new();
+    for event in events {
+        apply_event(&mut state, &event);
+    }
+    state
+}
+
+/// Index of the first event of the round in progress, events before it
+/// cannot affect the heap state.
+fn current_round_start() -> u64 {
+    (0..event_count())
+        .rev()
+        .find(|index| {
+            matches!(
+                get_event(*index).map(|e| e.payload),
+                Some(EventType::BlockSolved { .. })
+            )
+        })
+        .map(|index| index + 1)
+        .unwrap_or(0)
+}
+
+/// Rebuilds the heap state from the event log.
+pub fn replay_current_round() -> State {
+    replay((current_round_start()..event_count()).filter_map(get_event))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINER: Principal = Principal::from_slice(&[0x01]);
+    const OTHER_MINER: Principal = Principal::from_slice(&[0x02]);
+
+    fn submitted(miner: Principal, cycles: u64) -> Event {
+        Event {
+            timestamp: 0,
+            payload: EventType::CyclesSubmitted { miner, cycles },
+        }
+    }
+
+    #[test]
+    fn should_only_restore_cycles_submitted_since_the_last_block() {
+        let solved = Event {
+            timestamp: 1,
+            payload: EventType::BlockSolved {
+                miner: MINER,
+                to: MINER,
+                rewards: 100,
+                total_cycles_burned: 5,
+                miner_cycles_burned: 5,
+            },
+        };
+        for event in [
+            submitted(MINER, 5),
+            solved,
+            submitted(OTHER_MINER, 3),
+            submitted(OTHER_MINER, 4),
+        ] {
+            record_event(event.payload, event.timestamp);
+        }
+
+        let state = replay_current_round();
+        assert_eq!(state.miner_to_burned_cycles.get(&MINER), None);
+        assert_eq!(state.miner_to_burned_cycles.get(&OTHER_MINER), Some(&7));
+    }
+}
@@ -1,16 +1,26 @@
+use crate::admin::{is_denied_miner, Subsystem};
+use crate::estimator::CachedConversionRate;
+use crate::events::{record_event, EventType};
 use crate::guard::TaskGuard;
+use crate::logs::{DEBUG, INFO};
 use crate::memory::{
-    get_block_to_mine, get_expire_map, get_miner_owner, insert_block_to_mine, push_block,
-    remove_block_to_mine, remove_expired_entries, should_mine, user_count,
+    blocks_mined_count, credit_claimable, extra_payout_count, get_block, get_block_to_mine,
+    get_miner_owner, insert_block_to_mine, is_claim_mode, is_paused, last_solved_challenge_ts,
+    mined_block_count, push_block, record_mined_block, record_payout, remove_block_to_mine,
+    remove_expired_entries, should_mine, take_claimable, user_count,
 };
+use crate::payouts::PoolPayoutJob;
+use crate::rate_limit::RateLimiter;
+use crate::reconciliation::{payout_memo, Payout};
+use crate::runtime::{CdkRuntime, Runtime};
+use crate::streams::ExtraReward;
 use crate::tasks::{schedule_after, schedule_now, TaskType};
 use candid::{CandidType, Decode, Encode, Nat, Principal};
 use cycles_minting_canister::NotifyError;
-use ic_ledger_core::block::BlockType;
+use ic_canister_log::log;
 use ic_types::Cycles;
-use icrc_ledger_client_cdk::{CdkRuntime, ICRC1Client};
 use icrc_ledger_types::icrc1::account::Account;
-use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
+use icrc_ledger_types::icrc1::transfer::{Memo, TransferArg, TransferError};
 use rand::distributions::Standard;
 use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
@@ -25,11 +35,19 @@ use std::time::Duration;
 // Initial reward per block of 600 BOB
 const COINBASE_REWARDS: u64 = 60_000_000_000;
 pub const BLOCK_HALVING: u64 = 17_500;
+// Blocks mined by the first version of the minter.
+const HISTORICAL_BLOCKS: u64 = 1_441;
+// Number of recent blocks used to estimate the block speed.
+const BLOCK_SPEED_SAMPLE: u64 = 100;
 
 pub const SEC_NANOS: u64 = 1_000_000_000;
 pub const DAY_NANOS: u64 = 24 * 60 * 60 * SEC_NANOS;
 
-const CYCLES_PER_USER_PER_ROUND: u64 = 15_000_000_000;
+pub(crate) const CYCLES_PER_USER_PER_ROUND: u64 = 15_000_000_000;
+/// Price of one day in the pool.
+pub const E8S_PER_POOL_DAY: u64 = 100_000_000;
+/// Memo expected by the cycles minting canister for top ups.
+pub(crate) const MEMO_TOP_UP_CANISTER: u64 = 1347768404;
 
 pub const MAINNET_LEDGER_CANISTER_ID: Principal =
     Principal::from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x01, 0x01]);
@@ -37,10 +55,25 @@ pub const MAINNET_LEDGER_CANISTER_ID: Principal =
 pub const MAINNET_CYCLE_MINTER_CANISTER_ID: Principal =
     Principal::from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x01, 0x01]);
 
+pub mod admin;
+pub mod estimator;
+pub mod events;
 pub mod guard;
+pub mod locks;
+pub mod logs;
 pub mod memory;
+pub mod metrics;
 pub mod miner;
+pub mod payment;
+pub mod payouts;
+pub mod rate_limit;
+pub mod reconciliation;
+pub mod renewal;
+pub mod runtime;
+pub mod snapshot;
+pub mod streams;
 pub mod tasks;
+pub mod vesting;
 
 #[derive(Debug, Clone)]
 pub struct MinerWasm;
@@ -66,6 +99,68 @@ pub fn next_block_time(seed: [u8; 32]) -> u64 {
     clamped_sample as u64
 }
 
+/// Reward of the next block once `blocks_mined` blocks were mined.
+pub fn block_reward(blocks_mined: u64) -> u64 {
+    let halvings = blocks_mined / BLOCK_HALVING;
+    COINBASE_REWARDS
+        .checked_shr(halvings.try_into().unwrap_or(u32::MAX))
+        .unwrap_or(0)
+}
+
+/// Total rewards emitted by the first `blocks_mined` blocks.
+pub fn emitted_rewards(blocks_mined: u64) -> u64 {
+    let mut total: u64 = 0;
+    let mut remaining = blocks_mined;
+    let mut halvings: u32 = 0;
+    while remaining > 0 {
+        let reward = COINBASE_REWARDS.checked_shr(halvings).unwrap_or(0);
+        if reward == 0 {
+            break;
+        }
+        let blocks_in_era = remaining.min(BLOCK_HALVING);
+        total = total.saturating_add(blocks_in_era * reward);
+        remaining -= blocks_in_era;
+        halvings += 1;
+    }
+    total
+}
+
+/// Rewards emitted once every halving era is exhausted.
+pub fn max_supply() -> u64 {
+    emitted_rewards(u64::MAX)
+}
+
+/// Average time in seconds between the most recent blocks of the log.
+pub fn average_block_speed() -> u64 {
+    let count = mined_block_count();
+    if count < 2 {
+        return 0;
+    }
+    let first_index = count.saturating_sub(BLOCK_SPEED_SAMPLE);
+    let last_index = count - 1;
+    match (get_block(first_index), get_block(last_index)) {
+        (Some(first), Some(last)) if last_index > first_index => {
+            last.timestamp.saturating_sub(first.timestamp) / (last_index - first_index) / SEC_NANOS
+        }
+        _ => 0,
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn timestamp_nanos() -> u64 {
+    ic_cdk::api::time()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn timestamp_nanos() -> u64 {
+    use std::time::SystemTime;
+
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+}
+
 pub fn timer() {
     if let Some(task) = tasks::pop_if_ready() {
         let task_type = task.task_type;
@@ -77,10 +172,101 @@ pub fn timer() {
                         Err(_) => return,
                     };
 
-                    let _ = mine_block().await;
+                    if let Err(e) = mine_block(&CdkRuntime).await {
+                        log!(DEBUG, "[MineBob] {e}");
+                    }
+                });
+            }
+            TaskType::RenewMemberships => {
+                if is_paused(Subsystem::PoolJoins) {
+                    schedule_after(Duration::from_secs(10 * 60), TaskType::RenewMemberships);
+                    return;
+                }
+                ic_cdk::spawn(async move {
+                    let _guard = match TaskGuard::new(task_type) {
+                        Ok(guard) => guard,
+                        Err(_) => return,
+                    };
+
+                    let _enqueue_followup_guard = scopeguard::guard((), |_| {
+                        schedule_after(Duration::from_secs(10 * 60), TaskType::RenewMemberships);
+                    });
+
+                    renewal::renew_memberships(&CdkRuntime).await;
+                });
+            }
+            TaskType::CompletePayments => {
+                ic_cdk::spawn(async move {
+                    let _guard = match TaskGuard::new(task_type) {
+                        Ok(guard) => guard,
+                        Err(_) => return,
+                    };
+
+                    if payment::complete_pending_payments(&CdkRuntime).await {
+                        schedule_after(Duration::from_secs(30), TaskType::CompletePayments);
+                    }
+                });
+            }
+            TaskType::InstallMiners => {
+                ic_cdk::spawn(async move {
+                    let _guard = match TaskGuard::new(task_type) {
+                        Ok(guard) => guard,
+                        Err(_) => return,
+                    };
+
+                    if payment::install_pending_miners(&CdkRuntime).await {
+                        schedule_after(
+                            Duration::from_secs(payment::INSTALL_RETRY_INTERVAL_SECS),
+                            TaskType::InstallMiners,
+                        );
+                    }
+                });
+            }
+            TaskType::ReleaseVested => {
+                ic_cdk::spawn(async move {
+                    let _guard = match TaskGuard::new(task_type) {
+                        Ok(guard) => guard,
+                        Err(_) => return,
+                    };
+
+                    if vesting::release_all_vested(&CdkRuntime).await {
+                        schedule_after(
+                            Duration::from_secs(vesting::RELEASE_INTERVAL_SECS),
+                            TaskType::ReleaseVested,
+                        );
+                    }
+                });
+            }
+            TaskType::Reconcile => {
+                ic_cdk::spawn(async move {
+                    let _guard = match TaskGuard::new(task_type) {
+                        Ok(guard) => guard,
+                        Err(_) => return,
+                    };
+
+                    let _enqueue_followup_guard = scopeguard::guard((), |_| {
+                        schedule_after(Duration::from_secs(60), TaskType::Reconcile);
+                    });
+
+                    match reconciliation::reconcile().await {
+                        Ok(true) => {
+                            schedule_after(Duration::from_secs(60 * 60), TaskType::Reconcile)
+                        }
+                        Ok(false) => schedule_after(Duration::from_secs(10), TaskType::Reconcile),
+                        Err(e) => {
+                            log!(INFO, "[Reconcile] failed to reconcile the ledger: {e}");
+                            schedule_after(Duration::from_secs(60), TaskType::Reconcile);
+                        }
+                    }
+
+                    scopeguard::ScopeGuard::into_inner(_enqueue_followup_guard);
                 });
             }
             TaskType::ProcessLogic => {
+                if is_paused(Subsystem::Lottery) {
+                    schedule_after(Duration::from_secs(60), TaskType::ProcessLogic);
+                    return;
+                }
                 ic_cdk::spawn(async move {
                     let _guard = match TaskGuard::new(task_type) {
                         Ok(guard) => guard,
@@ -91,7 +277,8 @@ pub fn timer() {
                         schedule_after(Duration::from_secs(5), TaskType::ProcessLogic);
                     });
 
-                    if process_logic().await.is_err() {
+                    if let Err(e) = process_logic(&CdkRuntime).await {
+                        log!(INFO, "[ProcessLogic] failed to process logic: {e}");
                         schedule_after(Duration::from_secs(5), TaskType::ProcessLogic);
                     }
 
@@ -102,8 +289,13 @@ pub fn timer() {
     }
 }
 
-fn burn_from_pool() {
-    remove_expired_entries(ic_cdk::api::time());
+/// Cycles burned for the pool members when the round is drawn.
+pub fn pending_pool_burn() -> u64 {
+    CYCLES_PER_USER_PER_ROUND * user_count()
+}
+
+fn burn_from_pool<R: Runtime>(runtime: &R) {
+    remove_expired_entries(runtime.time());
     let user_count_u64 = user_count();
 
     if user_count_u64 == 0 {
@@ -112,7 +304,7 @@ fn burn_from_pool() {
 
     let cycles_per_round = CYCLES_PER_USER_PER_ROUND * user_count_u64;
 
-    let burned_cycles = ic_cdk::api::cycles_burn(cycles_per_round as u128) as u64;
+    let burned_cycles = runtime.cycles_burn(cycles_per_round as u128) as u64;
 
     let pool_id = Principal::from_text("zje3u-qaaaa-aaaai-acr2a-cai").unwrap();
 
@@ -122,50 +314,69 @@ fn burn_from_pool() {
             .and_modify(|e| *e += burned_cycles)
             .or_insert(burned_cycles);
     });
+    record_event(
+        EventType::CyclesSubmitted {
+            miner: pool_id,
+            cycles: burned_cycles,
+        },
+        runtime.time(),
+    );
+
+    log!(
+        DEBUG,
+        "[burn_from_pool] burned {burned_cycles} cycles for {user_count_u64} pool users"
+    );
 }
 
-pub async fn process_logic() -> Result<(), String> {
-    use ic_cdk::api::management_canister::main::raw_rand;
-
-    if let Ok((random_array,)) = raw_rand().await {
-        burn_from_pool();
-        let total_cycles: u64 = read_state(|s| s.miner_to_burned_cycles.values().sum());
-        if total_cycles == 0 {
+pub async fn process_logic<R: Runtime>(runtime: &R) -> Result<(), String> {
+    if let Ok(random_array) = runtime.raw_rand().await {
+        burn_from_pool(runtime);
+        let total_cycles: u64 = read_state(|s| s.eligible_burned_cycles())
+            .iter()
+            .map(|(_, cycles)| cycles)
+            .sum();
+        let mut entries = read_state(|s| s.effective_weights(runtime.time()));
+        let total_weight: u64 = entries.iter().map(|(_, weight)| weight).sum();
+        if total_weight == 0 {
             return Err("No cycles burned".to_string());
         }
 
-        let random_value = u64::from_le_bytes(random_array[..8].try_into().unwrap()) % total_cycles;
-
-        let selected_key = read_state(|s| {
-            let mut entries: Vec<_> = s.miner_to_burned_cycles.iter().collect();
-            let seed: [u8; 32] = random_array.clone().try_into().unwrap();
-            let mut rng = ChaCha20Rng::from_seed(seed);
-
-            entries.shuffle(&mut rng);
-
-            let mut cumulative_sum = 0;
-            entries
-                .into_iter()
-                .find(|(_, &value)| {
-                    cumulative_sum += value;
-                    cumulative_sum > random_value
-                })
-                .map(|(key, _)| *key)
-        })
-        .ok_or("No key selected")?;
-
-        if let Some(to) = get_miner_owner(selected_key) {
-            let miner_cycles_burned =
-                read_state(|s| *s.miner_to_burned_cycles.get(&selected_key).unwrap_or(&0));
-            mutate_state(|s| {
-                s.challenge_solved(selected_key, to, total_cycles, miner_cycles_burned)
-            });
-            let next_block = next_block_time(random_array.try_into().unwrap());
-            schedule_now(TaskType::MineBob);
-            schedule_after(Duration::from_secs(next_block), TaskType::ProcessLogic);
-        } else {
-            return Err("failed to find owner".to_string());
-        }
+        let random_value = u64::from_le_bytes(random_array[..8].try_into().unwrap()) % total_weight;
+
+        let mut rng = ChaCha20Rng::from_seed(random_array);
+        entries.shuffle(&mut rng);
+
+        let mut cumulative_sum = 0;
+        let selected_key = entries
+            .into_iter()
+            .find(|(_, value)| {
+                cumulative_sum += value;
+                cumulative_sum > random_value
+            })
+            .map(|(key, _)| key)
+            .ok_or("No key selected")?;
+
+        // Canisters mining through `submit_cycles` are not spawned by the
+        // minter and are rewarded directly.
+        let to = get_miner_owner(selected_key).unwrap_or(selected_key);
+        let miner_cycles_burned =
+            read_state(|s| *s.miner_to_burned_cycles.get(&selected_key).unwrap_or(&0));
+        mutate_state(|s| {
+            s.challenge_solved(
+                selected_key,
+                to,
+                total_cycles,
+                miner_cycles_burned,
+                runtime.time(),
+            )
+        });
+        let next_block = next_block_time(random_array);
+        log!(
+            INFO,
+            "[process_logic] block solved by miner {selected_key} of {to} with {miner_cycles_burned}/{total_cycles} cycles, next round in {next_block}s"
+        );
+        schedule_now(TaskType::MineBob);
+        schedule_after(Duration::from_secs(next_block), TaskType::ProcessLogic);
     } else {
         return Err("Failed to generate random value".to_string());
     }
@@ -173,115 +384,205 @@ pub async fn process_logic() -> Result<(), String> {
     Ok(())
 }
 
-pub async fn transfer(
+pub async fn transfer<R: Runtime>(
+    runtime: &R,
     to: impl Into<Account>,
     amount: Nat,
     fee: Option<Nat>,
+    memo: Option<Memo>,
     ledger_canister_id: Principal,
 ) -> Result<u64, TransferError> {
-    let client = ICRC1Client {
-        runtime: CdkRuntime,
-        ledger_canister_id,
-    };
-    let block_index = client
-        .transfer(TransferArg {
-            from_subaccount: None,
-            to: to.into(),
-            fee,
-            created_at_time: None,
-            memo: None,
-            amount,
-        })
+    runtime
+        .icrc1_transfer(
+            ledger_canister_id,
+            TransferArg {
+                from_subaccount: None,
+                to: to.into(),
+                fee,
+                created_at_time: None,
+                memo,
+                amount,
+            },
+        )
         .await
-        .map_err(|e| TransferError::GenericError {
-            error_code: (Nat::from(e.0 as u32)),
-            message: (e.1),
-        })??;
-    Ok(block_index.0.try_into().unwrap())
 }
 
-pub async fn mine_block() -> Result<(), String> {
-    if !should_mine() {
+/// Adds rewards to the claimable balance of `to`, see `claim_rewards`.
+pub(crate) fn credit_reward(block_timestamp: u64, to: Principal, amount: u64, now: u64) {
+    credit_claimable(to, amount);
+    record_event(
+        EventType::RewardCredited {
+            block_timestamp,
+            to,
+            amount,
+        },
+        now,
+    );
+}
+
+pub async fn mine_block<R: Runtime>(runtime: &R) -> Result<(), String> {
+    if !should_mine() && extra_payout_count() == 0 {
         return Err("nothing to do".to_string());
     }
 
+    if is_paused(Subsystem::Payouts) {
+        return Err("payouts are paused".to_string());
+    }
+
     let blocks = get_block_to_mine();
     let ledger_canister_id = read_state(|s| s.bob_ledger_id);
+    let mut pool_payout_pending = false;
     for block in blocks {
         let pool_id = Principal::from_text("zje3u-qaaaa-aaaai-acr2a-cai").unwrap();
         if block.to == pool_id {
-            let now = ic_cdk::api::time();
-            remove_expired_entries(now);
-            let user_count_u64 = user_count();
-            let reward = block.rewards / user_count_u64;
-            for (owner, _) in get_expire_map() {
-                if transfer(
-                    owner,
-                    reward.into(),
+            if !payouts::pay_pool_block(runtime, &block).await {
+                pool_payout_pending = true;
+            }
+        } else {
+            let (amount, vested) = vesting::split_reward(block.rewards);
+            if is_claim_mode(block.to) {
+                credit_reward(block.timestamp, block.to, amount, runtime.time());
+                log!(
+                    INFO,
+                    "[mine_block] credited {amount} to the claimable balance of {}",
+                    block.to
+                );
+            } else {
+                match transfer(
+                    runtime,
+                    block.to,
+                    amount.into(),
                     Some(Nat::from(0_u8)),
+                    Some(Memo::from(payout_memo(block.timestamp))),
                     ledger_canister_id,
                 )
                 .await
-                .is_err()
-                {}
+                {
+                    Ok(ledger_index) => {
+                        record_payout(
+                            ledger_index,
+                            Payout {
+                                block_timestamp: block.timestamp,
+                                to: block.to,
+                                amount,
+                            },
+                        );
+                        record_event(
+                            EventType::RewardPaid {
+                                block_timestamp: block.timestamp,
+                                to: block.to,
+                                amount,
+                                ledger_index,
+                            },
+                            runtime.time(),
+                        );
+                        log!(
+                            INFO,
+                            "[mine_block] paid {amount} to {} at ledger block {ledger_index}",
+                            block.to
+                        );
+                    }
+                    Err(e) => {
+                        log!(
+                            INFO,
+                            "[mine_block] failed to transfer {amount} to {}: {e:?}, retrying in 15s",
+                            block.to
+                        );
+                        mutate_state(|s| s.payout_failures += 1);
+                        schedule_after(Duration::from_secs(15), TaskType::MineBob);
+                        continue;
+                    }
+                }
             }
+            vesting::vest_reward(block.timestamp, block.to, vested, runtime.time());
+            queue_block_extra_rewards(&block);
             remove_block_to_mine(block.clone());
             push_block(block);
-        } else {
-            match transfer(
-                block.to,
-                block.rewards.into(),
-                Some(Nat::from(0_u8)),
-                ledger_canister_id,
-            )
-            .await
-            {
-                Ok(_) => {
-                    remove_block_to_mine(block.clone());
-                    push_block(block);
-                }
-                Err(_e) => {
-                    schedule_after(Duration::from_secs(15), TaskType::MineBob);
-                }
-            }
         }
     }
+    if pool_payout_pending {
+        schedule_now(TaskType::MineBob);
+    }
+    if let Some(retry_at) = streams::pay_extra_rewards(runtime).await {
+        let delay_secs = retry_at.saturating_sub(runtime.time()) / SEC_NANOS;
+        schedule_after(Duration::from_secs(delay_secs.max(15)), TaskType::MineBob);
+    }
     Ok(())
 }
 
-#[derive(CandidType)]
-struct NotifyTopUp {
-    block_index: u64,
-    canister_id: Principal,
+/// Queues the secondary rewards of a block won by a single principal.
+fn queue_block_extra_rewards(block: &Block) {
+    if let Some(extra_rewards) = &block.extra_rewards {
+        streams::queue_extra_rewards(block.timestamp, extra_rewards, block.to, 1);
+    }
 }
 
-pub async fn fetch_block(block_height: u64) -> Result<icp_ledger::Block, String> {
-    let args = Encode!(&icrc_ledger_types::icrc3::blocks::GetBlocksRequest {
-        start: block_height.into(),
-        length: Nat::from(1_u8),
-    })
-    .unwrap();
+/// Transfers the claimable balance of `owner` to `to` in a single mint.
+/// The claim is recorded as a payout keyed by the claim time so that the
+/// reconciliation can match it.
+pub async fn claim_rewards<R: Runtime>(
+    runtime: &R,
+    owner: Principal,
+    to: Account,
+) -> Result<u64, String> {
+    if is_paused(Subsystem::Payouts) {
+        return Err("payouts are paused".to_string());
+    }
+
+    // Taken before the transfer so that a concurrent claim sees no balance.
+    let amount = take_claimable(owner);
+    if amount == 0 {
+        return Err("nothing to claim".to_string());
+    }
 
-    let result: Result<Vec<u8>, (i32, String)> = ic_cdk::api::call::call_raw(
-        Principal::from_text("qhbym-qaaaa-aaaaa-aaafq-cai").unwrap(),
-        "get_blocks",
-        args,
-        0,
+    let now = runtime.time();
+    let ledger_canister_id = read_state(|s| s.bob_ledger_id);
+    match transfer(
+        runtime,
+        to,
+        amount.into(),
+        Some(Nat::from(0_u8)),
+        Some(Memo::from(payout_memo(now))),
+        ledger_canister_id,
     )
     .await
-    .map_err(|(code, msg)| (code as i32, msg));
-    match result {
-        Ok(res) => {
-            let blocks = Decode!(&res, ic_icp_index::GetBlocksResponse).unwrap();
-            icp_ledger::Block::decode(blocks.blocks.first().expect("no block").clone())
+    {
+        Ok(ledger_index) => {
+            record_payout(
+                ledger_index,
+                Payout {
+                    block_timestamp: now,
+                    to: to.owner,
+                    amount,
+                },
+            );
+            record_event(
+                EventType::RewardsClaimed {
+                    owner,
+                    amount,
+                    ledger_index,
+                },
+                now,
+            );
+            log!(
+                INFO,
+                "[claim_rewards] {owner} claimed {amount} at ledger block {ledger_index}"
+            );
+            Ok(amount)
+        }
+        Err(e) => {
+            credit_claimable(owner, amount);
+            Err(format!("failed to transfer {amount}: {e:?}"))
         }
-        Err((code, msg)) => Err(format!(
-            "Error while calling minter canister ({}): {:?}",
-            code, msg
-        )),
     }
 }
 
+#[derive(CandidType)]
+struct NotifyTopUp {
+    block_index: u64,
+    canister_id: Principal,
+}
+
 pub async fn notify_top_up(block_height: u64) -> Result<Cycles, String> {
     let canister_id = ic_cdk::id();
     let args = Encode!(&NotifyTopUp {
@@ -322,6 +623,53 @@ pub struct Block {
     pub total_cycles_burned: Option<u64>,
     pub miner_cycles_burned: Option<u64>,
     pub miner_count: Option<u64>,
+    /// Rewards of the partner streams paid alongside BOB, see `streams`.
+    pub extra_rewards: Option<Vec<ExtraReward>>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct EmissionInfo {
+    pub current_rewards: u64,
+    pub halving_count: u64,
+    pub block_halving: u64,
+    pub blocks_until_next_halving: u64,
+    pub estimated_secs_until_next_halving: u64,
+    pub total_mined: u64,
+    pub max_supply: u64,
+}
+
+#[derive(Clone, CandidType, Eq, PartialEq, Deserialize, Serialize, Debug)]
+pub struct MinerStats {
+    pub last_submission_ts: Option<u64>,
+    /// Round during which the miner last submitted burned cycles, or the
+    /// round it was spawned in if it never submitted.
+    pub last_submission_round: u64,
+    pub total_cycles_submitted: u64,
+}
+
+impl MinerStats {
+    pub fn new(round: u64) -> Self {
+        Self {
+            last_submission_ts: None,
+            last_submission_round: round,
+            total_cycles_submitted: 0,
+        }
+    }
+
+    /// Number of completed rounds since the last submission. The round in
+    /// progress is not counted as missed.
+    pub fn consecutive_missed_rounds(&self, current_round: u64) -> u64 {
+        current_round.saturating_sub(self.last_submission_round.saturating_add(1))
+    }
+}
+
+/// Lifetime accounting of a pool member, kept after the membership expires.
+#[derive(Clone, Default, CandidType, Eq, PartialEq, Deserialize, Serialize, Debug)]
+pub struct PoolMemberStats {
+    pub amount_paid_e8s: u64,
+    pub rewards_earned: u64,
+    #[serde(default)]
+    pub cycles_paid: u64,
 }
 
 #[derive(CandidType, Deserialize, Debug)]
@@ -333,6 +681,9 @@ pub struct Stats {
     pub cycle_balance: u64,
     pub time_since_last_block: u64,
     pub pending_blocks: Vec<Block>,
+    /// Progress of the payout of the block being paid to the pool members.
+    pub pool_payout: Option<PoolPayoutJob>,
+    pub pool_payout_concurrency: u64,
 }
 
 #[derive(Clone, CandidType, Deserialize, Serialize, Debug)]
@@ -341,64 +692,123 @@ pub struct State {
 
     pub miner_to_burned_cycles: BTreeMap<Principal, u64>,
 
-    pub miner_to_mined_block: BTreeMap<Principal, u64>,
+    pub principal_guards: BTreeSet<Principal>,
+    pub active_tasks: BTreeSet<TaskType>,
 
-    pub principal_to_miner: BTreeMap<Principal, Vec<Principal>>,
-    pub miner_to_owner: BTreeMap<Principal, Principal>,
+    pub payout_failures: u64,
 
-    pub last_solved_challenge_ts: u64,
+    pub rate_limiter: RateLimiter,
 
-    pub miner_block_index: BTreeSet<u64>,
+    pub conversion_rate: Option<CachedConversionRate>,
+}
 
-    pub principal_guards: BTreeSet<Principal>,
-    pub active_tasks: BTreeSet<TaskType>,
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl State {
-    pub fn new(now: u64) -> Self {
+    pub fn new() -> Self {
         Self {
             bob_ledger_id: Principal::from_text("7pail-xaaaa-aaaas-aabmq-cai").unwrap(),
 
             miner_to_burned_cycles: BTreeMap::default(),
 
-            miner_to_mined_block: BTreeMap::default(),
-
-            principal_to_miner: BTreeMap::default(),
-            miner_to_owner: BTreeMap::default(),
+            active_tasks: BTreeSet::default(),
+            principal_guards: BTreeSet::default(),
 
-            last_solved_challenge_ts: now,
+            payout_failures: 0,
 
-            miner_block_index: BTreeSet::default(),
+            rate_limiter: RateLimiter::default(),
 
-            active_tasks: BTreeSet::default(),
-            principal_guards: BTreeSet::default(),
+            conversion_rate: None,
         }
     }
 
     pub fn block_mined_count(&self) -> u64 {
-        self.miner_to_mined_block.values().sum()
+        blocks_mined_count()
     }
 
     pub fn total_blocks_mined(&self) -> u64 {
-        const HISTORICAL_BLOCKS: u64 = 1_441;
         self.block_mined_count() + HISTORICAL_BLOCKS
     }
 
-    pub fn new_miner(&mut self, miner: Principal, caller: Principal, block_index: u64) {
-        self.miner_block_index.insert(block_index);
-        self.miner_to_owner.insert(miner, caller);
-        self.principal_to_miner
-            .entry(caller)
-            .or_default()
-            .push(miner);
+    pub fn current_rewards(&self) -> u64 {
+        block_reward(self.total_blocks_mined())
     }
 
-    pub fn current_rewards(&self) -> u64 {
-        COINBASE_REWARDS >> (self.total_blocks_mined() / BLOCK_HALVING)
+    pub fn emission_info(&self, average_block_speed: u64) -> EmissionInfo {
+        let total_blocks_mined = self.total_blocks_mined();
+        let blocks_until_next_halving = BLOCK_HALVING - total_blocks_mined % BLOCK_HALVING;
+        EmissionInfo {
+            current_rewards: self.current_rewards(),
+            halving_count: total_blocks_mined / BLOCK_HALVING,
+            block_halving: BLOCK_HALVING,
+            blocks_until_next_halving,
+            estimated_secs_until_next_halving: blocks_until_next_halving
+                .saturating_mul(average_block_speed),
+            total_mined: emitted_rewards(total_blocks_mined),
+            max_supply: max_supply(),
+        }
     }
 
     pub fn time_since_last_block(&self) -> u64 {
-        (ic_cdk::api::time() - self.last_solved_challenge_ts) / SEC_NANOS
+        ic_cdk::api::time().saturating_sub(last_solved_challenge_ts()) / SEC_NANOS
+    }
+
+    /// Cycles burned during the round by the miners that are not on the
+    /// deny list, in principal order.
+    pub fn eligible_burned_cycles(&self) -> Vec<(Principal, u64)> {
+        self.miner_to_burned_cycles
+            .iter()
+            .filter(|(miner, _)| !is_denied_miner(**miner))
+            .map(|(miner, cycles)| (*miner, *cycles))
+            .collect()
+    }
+
+    /// Lottery weight of the eligible miners: their burned cycles times the
+    /// multiplier earned by their owner locking BOB, see `locks`.
+    pub fn effective_weights(&self, now: u64) -> Vec<(Principal, u64)> {
+        self.eligible_burned_cycles()
+            .into_iter()
+            .map(|(miner, cycles)| {
+                let multiplier_bps = get_miner_owner(miner)
+                    .map_or(locks::BPS, |owner| locks::active_multiplier_bps(owner, now));
+                (miner, locks::apply_multiplier(cycles, multiplier_bps))
+            })
+            .collect()
+    }
+
+    /// Probability that every eligible miner solves the round in progress
+    /// if nobody burns more cycles, including the cycles burned for the pool
+    /// at draw time. The weights are computed once for all the miners.
+    pub fn win_probabilities(&self, pending_pool_burn: u64, now: u64) -> BTreeMap<Principal, f64> {
+        let pool_id = Principal::from_text("zje3u-qaaaa-aaaai-acr2a-cai").unwrap();
+        let mut weights: BTreeMap<Principal, u64> =
+            self.effective_weights(now).into_iter().collect();
+        if pending_pool_burn > 0 {
+            *weights.entry(pool_id).or_default() += pending_pool_burn;
+        }
+        let total: u64 = weights.values().sum();
+        weights
+            .into_iter()
+            .map(|(miner, weight)| {
+                let probability = if total == 0 {
+                    0.0
+                } else {
+                    weight as f64 / total as f64
+                };
+                (miner, probability)
+            })
+            .collect()
+    }
+
+    pub fn win_probability(&self, miner: Principal, pending_pool_burn: u64, now: u64) -> f64 {
+        self.win_probabilities(pending_pool_burn, now)
+            .get(&miner)
+            .copied()
+            .unwrap_or(0.0)
     }
 
     pub fn challenge_solved(
@@ -407,22 +817,31 @@ impl State {
         to: Principal,
         total_cycles_burned: u64,
         cycles_burned: u64,
+        now: u64,
     ) {
         let rewards = self.current_rewards();
+        let extra_rewards = streams::reserve_extra_rewards(self.total_blocks_mined());
         insert_block_to_mine(Block {
             miner: Some(by),
             to,
             rewards,
-            timestamp: ic_cdk::api::time(),
+            timestamp: now,
             total_cycles_burned: Some(total_cycles_burned),
             miner_cycles_burned: Some(cycles_burned),
             miner_count: Some(self.miner_to_burned_cycles.len() as u64),
+            extra_rewards: Some(extra_rewards),
         });
-        self.miner_to_mined_block
-            .entry(by)
-            .and_modify(|e| *e += 1)
-            .or_insert(1);
-        self.last_solved_challenge_ts = ic_cdk::api::time();
+        record_mined_block(by, now);
+        record_event(
+            EventType::BlockSolved {
+                miner: by,
+                to,
+                rewards,
+                total_cycles_burned,
+                miner_cycles_burned: cycles_burned,
+            },
+            now,
+        );
         self.miner_to_burned_cycles = BTreeMap::default();
     }
 }
@@ -446,3 +865,500 @@ pub fn replace_state(state: State) {
         *s.borrow_mut() = Some(state);
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin::DenyListEntry;
+    use crate::memory::{
+        blocks_mined_count, event_count, get_bob_lock, get_claimable, get_event, get_mined_block,
+        get_payout, get_pool_payout_job, get_vesting_schedules_of, insert_deny_list_entry,
+        insert_expiration, insert_new_miner, insert_reward_stream, pool_payout_queue_len,
+        record_mined_block, set_claim_mode, set_pool_payout_concurrency, set_vesting_params,
+    };
+    use crate::runtime::mock::MockRuntime;
+    use crate::streams::RewardStream;
+    use futures::executor::block_on;
+
+    const MINER: Principal = Principal::from_slice(&[0x01]);
+    const OWNER: Principal = Principal::from_slice(&[0x02]);
+    const IDLE_MINER: Principal = Principal::from_slice(&[0x03]);
+    const IDLE_OWNER: Principal = Principal::from_slice(&[0x04]);
+
+    fn setup() -> MockRuntime {
+        replace_state(State::new());
+        insert_new_miner(MINER, OWNER, 1);
+        insert_new_miner(IDLE_MINER, IDLE_OWNER, 2);
+        MockRuntime::new(DAY_NANOS)
+    }
+
+    fn submit_burned_cycles(miner: Principal, cycles: u64) {
+        mutate_state(|s| s.miner_to_burned_cycles.insert(miner, cycles));
+    }
+
+    fn last_event() -> EventType {
+        get_event(event_count() - 1).unwrap().payload
+    }
+
+    fn pending_block(to: Principal, rewards: u64, timestamp: u64) -> Block {
+        Block {
+            to,
+            miner: Some(MINER),
+            miner_cycles_burned: Some(1_000_000_000),
+            total_cycles_burned: Some(1_000_000_000),
+            timestamp,
+            rewards,
+            miner_count: Some(1),
+            extra_rewards: None,
+        }
+    }
+
+    #[test]
+    fn should_halve_rewards_every_era() {
+        assert_eq!(block_reward(0), COINBASE_REWARDS);
+        assert_eq!(block_reward(BLOCK_HALVING - 1), COINBASE_REWARDS);
+        assert_eq!(block_reward(BLOCK_HALVING), COINBASE_REWARDS / 2);
+        assert_eq!(block_reward(3 * BLOCK_HALVING), COINBASE_REWARDS / 8);
+        assert_eq!(block_reward(64 * BLOCK_HALVING), 0);
+        assert_eq!(block_reward(u64::MAX), 0);
+    }
+
+    #[test]
+    fn should_sum_emitted_rewards_across_eras() {
+        assert_eq!(emitted_rewards(0), 0);
+        assert_eq!(emitted_rewards(1), COINBASE_REWARDS);
+        assert_eq!(
+            emitted_rewards(BLOCK_HALVING + 2),
+            BLOCK_HALVING * COINBASE_REWARDS + 2 * (COINBASE_REWARDS / 2)
+        );
+        let supply = max_supply();
+        assert!(supply < 2 * BLOCK_HALVING * COINBASE_REWARDS);
+        assert!(supply > emitted_rewards(10 * BLOCK_HALVING));
+    }
+
+    #[test]
+    fn should_count_the_rounds_missed_since_the_last_submission() {
+        let stats = MinerStats::new(5);
+        assert_eq!(stats.consecutive_missed_rounds(3), 0);
+        assert_eq!(stats.consecutive_missed_rounds(5), 0);
+        assert_eq!(stats.consecutive_missed_rounds(6), 0);
+        assert_eq!(stats.consecutive_missed_rounds(9), 3);
+        assert_eq!(
+            MinerStats::new(u64::MAX).consecutive_missed_rounds(u64::MAX),
+            0
+        );
+    }
+
+    #[test]
+    fn should_select_a_miner_that_burned_cycles() {
+        let runtime = setup();
+        submit_burned_cycles(MINER, 5_000_000_000);
+        submit_burned_cycles(IDLE_MINER, 0);
+
+        block_on(process_logic(&runtime)).unwrap();
+
+        let pending = get_block_to_mine();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].to, OWNER);
+        assert_eq!(pending[0].miner, Some(MINER));
+        assert_eq!(pending[0].rewards, block_reward(HISTORICAL_BLOCKS));
+        assert_eq!(pending[0].timestamp, runtime.time());
+        assert_eq!(pending[0].miner_count, Some(2));
+        assert_eq!(blocks_mined_count(), 1);
+        assert!(read_state(|s| s.miner_to_burned_cycles.is_empty()));
+    }
+
+    #[test]
+    fn should_reward_the_owner_of_the_miner_or_the_miner_itself() {
+        let runtime = setup();
+        let canister = Principal::from_slice(&[0x05]);
+        submit_burned_cycles(MINER, 5_000_000_000);
+        block_on(process_logic(&runtime)).unwrap();
+        runtime.time.set(2 * DAY_NANOS);
+        submit_burned_cycles(canister, 5_000_000_000);
+        block_on(process_logic(&runtime)).unwrap();
+
+        let mut blocks = get_block_to_mine();
+        blocks.sort_by_key(|b| b.timestamp);
+        let rewarded: Vec<(Option<Principal>, Principal)> =
+            blocks.iter().map(|b| (b.miner, b.to)).collect();
+        assert_eq!(
+            rewarded,
+            vec![(Some(MINER), OWNER), (Some(canister), canister)]
+        );
+    }
+
+    #[test]
+    fn should_not_select_a_denied_owner() {
+        let runtime = setup();
+        submit_burned_cycles(MINER, 5_000_000_000);
+        submit_burned_cycles(IDLE_MINER, 1_000_000_000);
+        insert_deny_list_entry(DenyListEntry {
+            principal: OWNER,
+            reason: "abuse".to_string(),
+            added_at: 0,
+            added_by: IDLE_OWNER,
+        });
+
+        block_on(process_logic(&runtime)).unwrap();
+
+        let pending = get_block_to_mine();
+        assert_eq!(pending[0].miner, Some(IDLE_MINER));
+        assert_eq!(pending[0].total_cycles_burned, Some(1_000_000_000));
+    }
+
+    #[test]
+    fn should_not_solve_a_block_without_burned_cycles() {
+        let runtime = setup();
+
+        assert_eq!(
+            block_on(process_logic(&runtime)),
+            Err("No cycles burned".to_string())
+        );
+        assert!(get_block_to_mine().is_empty());
+    }
+
+    #[test]
+    fn should_halve_rewards_at_the_end_of_the_era() {
+        let runtime = setup();
+        for _ in 0..BLOCK_HALVING - HISTORICAL_BLOCKS - 1 {
+            record_mined_block(MINER, 0);
+        }
+
+        submit_burned_cycles(MINER, 5_000_000_000);
+        block_on(process_logic(&runtime)).unwrap();
+        runtime.time.set(2 * DAY_NANOS);
+        submit_burned_cycles(MINER, 5_000_000_000);
+        block_on(process_logic(&runtime)).unwrap();
+
+        let mut blocks = get_block_to_mine();
+        blocks.sort_by_key(|b| b.timestamp);
+        let rewards: Vec<u64> = blocks.iter().map(|b| b.rewards).collect();
+        assert_eq!(rewards, vec![COINBASE_REWARDS, COINBASE_REWARDS / 2]);
+    }
+
+    #[test]
+    fn should_pay_the_block_owner_with_the_block_memo() {
+        let runtime = setup();
+        let block = pending_block(OWNER, 100, 42);
+        insert_block_to_mine(block.clone());
+
+        block_on(mine_block(&runtime)).unwrap();
+
+        let transfers = runtime.transfers();
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].1.to, Account::from(OWNER));
+        assert_eq!(transfers[0].1.amount, Nat::from(100_u64));
+        assert_eq!(transfers[0].1.memo, Some(Memo::from(payout_memo(42))));
+        assert!(get_block_to_mine().is_empty());
+        assert_eq!(get_block(0), Some(block));
+        assert_eq!(
+            get_payout(0),
+            Some(Payout {
+                block_timestamp: 42,
+                to: OWNER,
+                amount: 100,
+            })
+        );
+    }
+
+    #[test]
+    fn should_pay_reward_streams_until_the_budget_runs_out() {
+        let runtime = setup();
+        let partner_ledger = Principal::from_slice(&[0x10]);
+        insert_reward_stream(RewardStream {
+            ledger_id: partner_ledger,
+            amount_per_block: 500,
+            budget: 800,
+            spent: 0,
+            start_block: 0,
+            end_block: None,
+            registered_at: 0,
+        });
+
+        submit_burned_cycles(MINER, 5_000_000_000);
+        block_on(process_logic(&runtime)).unwrap();
+        runtime.time.set(2 * DAY_NANOS);
+        submit_burned_cycles(MINER, 5_000_000_000);
+        block_on(process_logic(&runtime)).unwrap();
+        block_on(mine_block(&runtime)).unwrap();
+
+        let partner_transfers: Vec<(Account, Nat)> = runtime
+            .transfers()
+            .into_iter()
+            .filter(|(ledger_id, _)| *ledger_id == partner_ledger)
+            .map(|(_, arg)| (arg.to, arg.amount))
+            .collect();
+        assert_eq!(
+            partner_transfers,
+            vec![(Account::from(OWNER), Nat::from(500_u64))]
+        );
+        let extra_rewards: Vec<Option<Vec<ExtraReward>>> = get_mined_block()
+            .into_iter()
+            .map(|b| b.extra_rewards)
+            .collect();
+        assert_eq!(
+            extra_rewards,
+            vec![
+                Some(vec![ExtraReward {
+                    ledger_id: partner_ledger,
+                    amount: 500
+                }]),
+                Some(vec![]),
+            ]
+        );
+        assert_eq!(extra_payout_count(), 0);
+    }
+
+    #[test]
+    fn should_release_vested_rewards_linearly() {
+        let runtime = setup();
+        set_vesting_params(vesting::VestingParams {
+            vested_bps: 4_000,
+            duration_days: 10,
+        });
+        insert_block_to_mine(pending_block(OWNER, 1_000, 42));
+
+        block_on(mine_block(&runtime)).unwrap();
+        assert_eq!(runtime.transfers()[0].1.amount, Nat::from(600_u64));
+        assert!(block_on(vesting::release_vested(&runtime, OWNER)).is_err());
+
+        runtime.time.set(runtime.time() + 5 * DAY_NANOS);
+        assert_eq!(block_on(vesting::release_vested(&runtime, OWNER)), Ok(200));
+        let transfers = runtime.transfers();
+        assert_eq!(transfers[1].1.amount, Nat::from(200_u64));
+        assert_eq!(
+            transfers[1].1.memo,
+            Some(Memo::from(payout_memo(runtime.time())))
+        );
+
+        runtime.time.set(runtime.time() + 10 * DAY_NANOS);
+        assert!(!block_on(vesting::release_all_vested(&runtime)));
+        assert_eq!(runtime.transfers()[2].1.amount, Nat::from(200_u64));
+        assert!(get_vesting_schedules_of(OWNER).is_empty());
+    }
+
+    #[test]
+    fn should_keep_the_block_pending_when_the_transfer_fails() {
+        let runtime = setup();
+        runtime.fail_transfers.set(true);
+        insert_block_to_mine(pending_block(OWNER, 100, 42));
+
+        block_on(mine_block(&runtime)).unwrap();
+
+        assert_eq!(get_block_to_mine().len(), 1);
+        assert_eq!(mined_block_count(), 0);
+        assert_eq!(read_state(|s| s.payout_failures), 1);
+    }
+
+    #[test]
+    fn should_split_pool_rewards_between_members() {
+        let runtime = setup();
+        let pool_id = Principal::from_text("zje3u-qaaaa-aaaai-acr2a-cai").unwrap();
+        insert_expiration(OWNER, 2 * DAY_NANOS);
+        insert_expiration(IDLE_OWNER, 2 * DAY_NANOS);
+        insert_block_to_mine(pending_block(pool_id, 100, 42));
+
+        block_on(mine_block(&runtime)).unwrap();
+
+        let paid: Vec<(Account, Nat)> = runtime
+            .transfers()
+            .into_iter()
+            .map(|(_, arg)| (arg.to, arg.amount))
+            .collect();
+        assert_eq!(
+            paid,
+            vec![
+                (Account::from(OWNER), Nat::from(50_u64)),
+                (Account::from(IDLE_OWNER), Nat::from(50_u64)),
+            ]
+        );
+        assert_eq!(mined_block_count(), 1);
+    }
+
+    #[test]
+    fn should_resume_large_pool_payouts_across_runs() {
+        let runtime = setup();
+        let pool_id = Principal::from_text("zje3u-qaaaa-aaaai-acr2a-cai").unwrap();
+        set_pool_payout_concurrency(2);
+        for i in 0..25_u8 {
+            insert_expiration(Principal::from_slice(&[0x10, i]), 2 * DAY_NANOS);
+        }
+        insert_block_to_mine(pending_block(pool_id, 2_500, 42));
+
+        block_on(mine_block(&runtime)).unwrap();
+        let job = get_pool_payout_job().unwrap();
+        assert_eq!(job.member_count, 25);
+        assert_eq!(job.processed, 20);
+        assert_eq!(runtime.transfers().len(), 20);
+        assert_eq!(mined_block_count(), 0);
+
+        block_on(mine_block(&runtime)).unwrap();
+        assert_eq!(get_pool_payout_job(), None);
+        let paid: BTreeSet<Account> = runtime
+            .transfers()
+            .into_iter()
+            .map(|(_, arg)| arg.to)
+            .collect();
+        assert_eq!(paid.len(), 25);
+        assert_eq!(mined_block_count(), 1);
+    }
+
+    #[test]
+    fn should_credit_pool_rewards_that_failed_to_transfer() {
+        let runtime = setup();
+        let pool_id = Principal::from_text("zje3u-qaaaa-aaaai-acr2a-cai").unwrap();
+        insert_expiration(OWNER, 2 * DAY_NANOS);
+        insert_block_to_mine(pending_block(pool_id, 100, 42));
+        runtime.fail_transfers.set(true);
+
+        block_on(mine_block(&runtime)).unwrap();
+
+        assert_eq!(get_claimable(OWNER), 100);
+        assert_eq!(mined_block_count(), 1);
+    }
+
+    #[test]
+    fn should_only_vest_pool_rewards_once_the_transfer_succeeded() {
+        let runtime = setup();
+        let pool_id = Principal::from_text("zje3u-qaaaa-aaaai-acr2a-cai").unwrap();
+        set_vesting_params(vesting::VestingParams {
+            vested_bps: 4_000,
+            duration_days: 10,
+        });
+        set_pool_payout_concurrency(1);
+        insert_expiration(OWNER, 2 * DAY_NANOS);
+        insert_expiration(IDLE_OWNER, 2 * DAY_NANOS);
+        insert_block_to_mine(pending_block(pool_id, 200, 42));
+        runtime.fail_transfers.set(true);
+
+        block_on(mine_block(&runtime)).unwrap();
+
+        assert_eq!(get_claimable(OWNER), 100);
+        assert_eq!(get_claimable(IDLE_OWNER), 100);
+        assert!(get_vesting_schedules_of(OWNER).is_empty());
+        assert_eq!(pool_payout_queue_len(), 0);
+
+        runtime.fail_transfers.set(false);
+        insert_block_to_mine(pending_block(pool_id, 200, 43));
+        block_on(mine_block(&runtime)).unwrap();
+
+        let paid: Vec<Nat> = runtime
+            .transfers()
+            .into_iter()
+            .filter(|(_, arg)| arg.to == Account::from(OWNER))
+            .map(|(_, arg)| arg.amount)
+            .collect();
+        assert_eq!(paid, vec![Nat::from(60_u64)]);
+        assert_eq!(get_vesting_schedules_of(OWNER)[0].total, 40);
+        assert_eq!(get_claimable(OWNER), 100);
+        assert_eq!(mined_block_count(), 2);
+    }
+
+    #[test]
+    fn should_claim_credited_rewards_in_a_single_transfer() {
+        let runtime = setup();
+        set_claim_mode(OWNER, true);
+        insert_block_to_mine(pending_block(OWNER, 100, 42));
+        insert_block_to_mine(pending_block(OWNER, 200, 43));
+
+        block_on(mine_block(&runtime)).unwrap();
+        assert!(runtime.transfers().is_empty());
+        assert_eq!(get_claimable(OWNER), 300);
+
+        let to = Account::from(IDLE_OWNER);
+        assert_eq!(block_on(claim_rewards(&runtime, OWNER, to)), Ok(300));
+        let transfers = runtime.transfers();
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].1.to, to);
+        assert_eq!(transfers[0].1.amount, Nat::from(300_u64));
+        assert_eq!(get_claimable(OWNER), 0);
+        assert!(block_on(claim_rewards(&runtime, OWNER, to)).is_err());
+    }
+
+    #[test]
+    fn should_restore_the_claimable_balance_when_the_claim_fails() {
+        let runtime = setup();
+        credit_claimable(OWNER, 100);
+        runtime.fail_transfers.set(true);
+
+        assert!(block_on(claim_rewards(&runtime, OWNER, Account::from(OWNER))).is_err());
+        assert_eq!(get_claimable(OWNER), 100);
+    }
+
+    #[test]
+    fn should_boost_the_weight_of_owners_locking_bob() {
+        let runtime = setup();
+        let minter_id = Principal::from_slice(&[0x20]);
+        submit_burned_cycles(MINER, 1_000);
+        submit_burned_cycles(IDLE_MINER, 1_000);
+
+        let lock = block_on(locks::lock_bob(
+            &runtime,
+            minter_id,
+            OWNER,
+            locks::FULL_BOOST_E8S,
+            locks::MAX_LOCK_DAYS,
+        ))
+        .unwrap();
+        assert_eq!(runtime.transfers_from.borrow()[0].1.to.owner, minter_id);
+        assert_eq!(
+            last_event(),
+            EventType::BobLocked {
+                owner: OWNER,
+                amount_e8s: locks::FULL_BOOST_E8S,
+                unlock_at: lock.unlock_at,
+                multiplier_bps: 2 * locks::BPS,
+                ledger_index: 0,
+            }
+        );
+        let now = runtime.time();
+        assert_eq!(
+            read_state(|s| s.effective_weights(now)),
+            vec![(MINER, 2_000), (IDLE_MINER, 1_000)]
+        );
+        assert!(block_on(locks::unlock_bob(&runtime, OWNER)).is_err());
+
+        runtime.time.set(lock.unlock_at);
+        read_state(|s| assert_eq!(s.win_probability(MINER, 0, lock.unlock_at), 0.5));
+        block_on(locks::unlock_bob(&runtime, OWNER)).unwrap();
+        let transfers = runtime.transfers();
+        assert_eq!(
+            transfers[0].1.from_subaccount,
+            Some(locks::lock_subaccount(OWNER))
+        );
+        assert_eq!(
+            transfers[0].1.amount,
+            Nat::from(locks::FULL_BOOST_E8S - locks::BOB_TRANSFER_FEE)
+        );
+        assert_eq!(transfers[0].1.fee, Some(Nat::from(locks::BOB_TRANSFER_FEE)));
+        assert_eq!(get_bob_lock(OWNER), None);
+        assert_eq!(
+            last_event(),
+            EventType::BobUnlocked {
+                owner: OWNER,
+                amount_e8s: locks::FULL_BOOST_E8S - locks::BOB_TRANSFER_FEE,
+                ledger_index: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn should_include_the_pending_pool_burn_in_the_win_probability() {
+        setup();
+        let pool_id = Principal::from_text("zje3u-qaaaa-aaaai-acr2a-cai").unwrap();
+        submit_burned_cycles(MINER, 3_000);
+        submit_burned_cycles(IDLE_MINER, 1_000);
+
+        read_state(|s| {
+            assert_eq!(s.win_probability(MINER, 0, 0), 0.75);
+            assert_eq!(s.win_probability(MINER, 4_000, 0), 0.375);
+            assert_eq!(s.win_probability(pool_id, 4_000, 0), 0.5);
+            assert_eq!(s.win_probability(OWNER, 4_000, 0), 0.0);
+            assert_eq!(
+                s.win_probabilities(4_000, 0),
+                BTreeMap::from([(MINER, 0.375), (IDLE_MINER, 0.125), (pool_id, 0.5)])
+            );
+        });
+    }
+}
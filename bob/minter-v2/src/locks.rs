@@ -0,0 +1,204 @@
+//! Weight boost for owners locking BOB. The BOB is transferred to a
+//! subaccount of the minter derived from the owner and the burned cycles of
+//! the owner's miners count with a bounded multiplier in the lottery until
+//! the lock expires. Owners unlock their BOB once the lock period is over.
+
+use crate::events::{record_event, EventType};
+use crate::logs::INFO;
+use crate::memory::{get_bob_lock, insert_bob_lock, remove_bob_lock};
+use crate::runtime::Runtime;
+use crate::{read_state, DAY_NANOS};
+use candid::{CandidType, Nat, Principal};
+use ic_canister_log::log;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use icrc_ledger_types::icrc1::transfer::TransferArg;
+use icrc_ledger_types::icrc2::transfer_from::TransferFromArgs;
+use serde::{Deserialize, Serialize};
+
+const E8S: u64 = 100_000_000;
+/// Transfer fee of the BOB ledger, paid when the BOB is unlocked. It is set
+/// explicitly on the transfer, so the ledger rejects the unlock rather than
+/// charging a different fee if its fee changes.
+pub const BOB_TRANSFER_FEE: u64 = 1_000_000;
+pub const MIN_LOCK_E8S: u64 = 100 * E8S;
+/// Amount above which locking more BOB does not increase the boost.
+pub const FULL_BOOST_E8S: u64 = 10_000 * E8S;
+pub const MIN_LOCK_DAYS: u64 = 7;
+pub const MAX_LOCK_DAYS: u64 = 365;
+/// Weight multipliers are expressed in basis points.
+pub const BPS: u64 = 10_000;
+/// Largest boost, reached by locking `FULL_BOOST_E8S` for `MAX_LOCK_DAYS`:
+/// the burned cycles of the owner's miners count twice.
+pub const MAX_BOOST_BPS: u64 = 10_000;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub struct BobLock {
+    pub amount_e8s: u64,
+    pub locked_at: u64,
+    pub unlock_at: u64,
+    /// Weight multiplier applied until `unlock_at`, in basis points.
+    pub multiplier_bps: u64,
+    pub ledger_index: u64,
+}
+
+/// Multiplier, in basis points, earned by locking `amount_e8s` for `days`.
+pub fn multiplier_bps(amount_e8s: u64, days: u64) -> u64 {
+    let boost = MAX_BOOST_BPS as u128 * amount_e8s.min(FULL_BOOST_E8S) as u128
+        / FULL_BOOST_E8S as u128
+        * days.min(MAX_LOCK_DAYS) as u128
+        / MAX_LOCK_DAYS as u128;
+    BPS + boost as u64
+}
+
+/// Multiplier applied to the burned cycles of the miners of `owner`.
+pub fn active_multiplier_bps(owner: Principal, now: u64) -> u64 {
+    get_bob_lock(owner)
+        .filter(|lock| now < lock.unlock_at)
+        .map_or(BPS, |lock| lock.multiplier_bps)
+}
+
+pub fn apply_multiplier(cycles: u64, multiplier_bps: u64) -> u64 {
+    (cycles as u128 * multiplier_bps as u128 / BPS as u128).min(u64::MAX as u128) as u64
+}
+
+/// Subaccount of the minter holding the BOB locked by `owner`.
+pub fn lock_subaccount(owner: Principal) -> Subaccount {
+    icp_ledger::Subaccount::from(&ic_types::PrincipalId(owner)).0
+}
+
+/// Locks `amount_e8s` BOB of `owner` for `days`, the owner must have
+/// approved the minter for the amount plus the transfer fee.
+pub async fn lock_bob<R: Runtime>(
+    runtime: &R,
+    minter_id: Principal,
+    owner: Principal,
+    amount_e8s: u64,
+    days: u64,
+) -> Result<BobLock, String> {
+    if amount_e8s < MIN_LOCK_E8S {
+        return Err(format!("the minimum lock is {MIN_LOCK_E8S} e8s"));
+    }
+    if !(MIN_LOCK_DAYS..=MAX_LOCK_DAYS).contains(&days) {
+        return Err(format!(
+            "the lock period must be between {MIN_LOCK_DAYS} and {MAX_LOCK_DAYS} days"
+        ));
+    }
+    if get_bob_lock(owner).is_some() {
+        return Err("BOB already locked, unlock it first".to_string());
+    }
+
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account::from(owner),
+        to: Account {
+            owner: minter_id,
+            subaccount: Some(lock_subaccount(owner)),
+        },
+        amount: Nat::from(amount_e8s),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let ledger_canister_id = read_state(|s| s.bob_ledger_id);
+    let ledger_index = runtime
+        .icrc2_transfer_from(ledger_canister_id, args)
+        .await
+        .map_err(|e| format!("failed to transfer {amount_e8s} BOB: {e:?}"))?;
+
+    let now = runtime.time();
+    let lock = BobLock {
+        amount_e8s,
+        locked_at: now,
+        unlock_at: now + days * DAY_NANOS,
+        multiplier_bps: multiplier_bps(amount_e8s, days),
+        ledger_index,
+    };
+    insert_bob_lock(owner, lock.clone());
+    record_event(
+        EventType::BobLocked {
+            owner,
+            amount_e8s,
+            unlock_at: lock.unlock_at,
+            multiplier_bps: lock.multiplier_bps,
+            ledger_index,
+        },
+        now,
+    );
+    log!(
+        INFO,
+        "[lock_bob] {owner} locked {amount_e8s} BOB for {days} days, multiplier {} bps",
+        lock.multiplier_bps
+    );
+    Ok(lock)
+}
+
+/// Returns the locked BOB of `owner` once the lock expired, minus the
+/// transfer fee. The lock is kept if the transfer fails.
+pub async fn unlock_bob<R: Runtime>(runtime: &R, owner: Principal) -> Result<u64, String> {
+    let lock = get_bob_lock(owner).ok_or_else(|| "no BOB locked".to_string())?;
+    let now = runtime.time();
+    if now < lock.unlock_at {
+        return Err(format!("BOB is locked until {}", lock.unlock_at));
+    }
+
+    remove_bob_lock(owner);
+    let amount = lock.amount_e8s.saturating_sub(BOB_TRANSFER_FEE);
+    let ledger_canister_id = read_state(|s| s.bob_ledger_id);
+    match runtime
+        .icrc1_transfer(
+            ledger_canister_id,
+            TransferArg {
+                from_subaccount: Some(lock_subaccount(owner)),
+                to: Account::from(owner),
+                fee: Some(Nat::from(BOB_TRANSFER_FEE)),
+                created_at_time: None,
+                memo: None,
+                amount: Nat::from(amount),
+            },
+        )
+        .await
+    {
+        Ok(ledger_index) => {
+            record_event(
+                EventType::BobUnlocked {
+                    owner,
+                    amount_e8s: amount,
+                    ledger_index,
+                },
+                now,
+            );
+            log!(
+                INFO,
+                "[unlock_bob] returned {amount} BOB to {owner} at ledger block {ledger_index}"
+            );
+            Ok(ledger_index)
+        }
+        Err(e) => {
+            insert_bob_lock(owner, lock);
+            Err(format!("failed to transfer {amount} BOB: {e:?}"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_bound_the_multiplier() {
+        assert_eq!(multiplier_bps(MIN_LOCK_E8S, 0), BPS);
+        assert_eq!(
+            multiplier_bps(FULL_BOOST_E8S, MAX_LOCK_DAYS),
+            BPS + MAX_BOOST_BPS
+        );
+        assert_eq!(
+            multiplier_bps(10 * FULL_BOOST_E8S, 10 * MAX_LOCK_DAYS),
+            BPS + MAX_BOOST_BPS
+        );
+        assert_eq!(
+            multiplier_bps(FULL_BOOST_E8S / 2, MAX_LOCK_DAYS),
+            BPS + MAX_BOOST_BPS / 2
+        );
+        assert_eq!(apply_multiplier(1_000, 15_000), 1_500);
+    }
+}
@@ -1,62 +1,83 @@
+use bob_minter_v2::admin::{
+    ensure_controller, ensure_controller_or_admin, is_denied_miner, record_admin_action,
+    AdminAction, AuditEntry, DenyListEntry, Subsystem,
+};
+use bob_minter_v2::estimator::{self, CostEstimate};
+use bob_minter_v2::events::{record_event, replay_current_round, Event, EventType};
 use bob_minter_v2::guard::GuardPrincipal;
+use bob_minter_v2::locks::{self, BobLock};
+use bob_minter_v2::logs::{DEBUG, INFO};
 use bob_minter_v2::memory::{
-    get_block, get_block_to_mine, get_expiration, get_miner_owner, get_miner_to_owner_and_index,
-    get_user_expiration, insert_block_index, insert_expiration, insert_new_miner, is_known_block,
-    mined_block_count, user_count,
+    audit_log_len, current_round, discrepancy_count, event_count, get_audit_entry, get_block,
+    get_block_to_mine, get_discrepancy, get_event, get_expire_map, get_expire_map_page,
+    get_mined_block_count_of, get_miner_owner, get_miner_stats, get_miner_to_owner_and_index,
+    get_miners_of, get_pool_member_stats, get_pool_payout_concurrency, get_pool_payout_job,
+    get_principal_to_miner, get_reconciliation_state, get_reward_stream, get_user_expiration,
+    get_vesting_schedules_of, init_stable_layout, insert_admin, insert_deny_list_entry,
+    insert_new_miner, insert_reward_stream, is_claim_mode, is_known_block, is_miner, is_paused,
+    last_solved_challenge_ts, migrate_to_stable_layout, mined_block_count, miner_count,
+    pause_subsystem, record_miner_submission, remove_deny_list_entry, resume_subsystem,
+    update_reward_stream, user_count,
 };
 use bob_minter_v2::miner::{
-    create_canister, install_code, reinstall_code, start_canister, stop_canister,
+    canister_status, reinstall_code, start_canister, stop_canister, MinerStatus,
 };
-use bob_minter_v2::tasks::{schedule_after, schedule_now, TaskType};
+use bob_minter_v2::payment::{self, PaymentKind, PendingPayment};
+use bob_minter_v2::payouts::MAX_POOL_PAYOUT_CONCURRENCY;
+use bob_minter_v2::reconciliation::{DiscrepancyRecord, ReconciliationState};
+use bob_minter_v2::renewal::{AutoRenewal, RenewalRecord};
+use bob_minter_v2::runtime::CdkRuntime;
+use bob_minter_v2::snapshot::SnapshotInfo;
+use bob_minter_v2::streams::{RewardStream, RewardStreamArgs};
+use bob_minter_v2::tasks::{get_deadline, schedule_after, schedule_now, TaskType};
+use bob_minter_v2::vesting::{self, VestingParams, VestingSchedule};
 use bob_minter_v2::{
-    fetch_block, miner_wasm, mutate_state, notify_top_up, read_state, replace_state, Block, State,
-    Stats, BLOCK_HALVING, DAY_NANOS, SEC_NANOS,
+    average_block_speed, miner_wasm, mutate_state, pending_pool_burn, read_state, replace_state,
+    Block, EmissionInfo, State, Stats, BLOCK_HALVING, SEC_NANOS,
 };
 use candid::{CandidType, Encode, Principal};
-use ic_cdk::{init, post_upgrade, query, update};
-use icp_ledger::{AccountIdentifier, Operation};
+use ic_canister_log::log;
+use ic_canisters_http_types::{HttpRequest, HttpResponse, HttpResponseBuilder};
+use ic_cdk::{init, inspect_message, post_upgrade, query, update};
+use icrc_ledger_types::icrc1::account::Account;
+use std::collections::BTreeMap;
 use std::time::Duration;
 
 fn main() {}
 
 #[post_upgrade]
 fn post_upgrade() {
-    let mut state = State::new(ic_cdk::api::time());
-
-    for (miner, (owner, index)) in get_miner_to_owner_and_index() {
-        state.new_miner(miner, owner, index);
-    }
+    migrate_to_stable_layout(ic_cdk::api::time());
 
-    for index in 0..mined_block_count() {
-        if let Some(block) = get_block(index) {
-            if let Some(miner) = block.miner {
-                state
-                    .miner_to_mined_block
-                    .entry(miner)
-                    .and_modify(|e| *e += 1)
-                    .or_insert(1);
-            }
-        }
-    }
-
-    replace_state(state);
+    replace_state(replay_current_round());
     setup_timer();
 }
 
 #[init]
 fn init() {
-    let state = State::new(ic_cdk::api::time());
+    init_stable_layout(ic_cdk::api::time());
 
     let pool_id = Principal::from_text("zje3u-qaaaa-aaaai-acr2a-cai").unwrap();
     insert_new_miner(pool_id, pool_id, 0);
 
-    replace_state(state);
+    replace_state(State::new());
     setup_timer();
 }
 
 fn setup_timer() {
     schedule_now(TaskType::MineBob);
     schedule_after(Duration::from_secs(300), TaskType::ProcessLogic);
+    schedule_after(Duration::from_secs(600), TaskType::Reconcile);
+    schedule_after(Duration::from_secs(60), TaskType::RenewMemberships);
+    schedule_after(Duration::from_secs(120), TaskType::CompletePayments);
+    schedule_after(
+        Duration::from_secs(vesting::RELEASE_INTERVAL_SECS),
+        TaskType::ReleaseVested,
+    );
+    schedule_after(
+        Duration::from_secs(payment::INSTALL_RETRY_INTERVAL_SECS),
+        TaskType::InstallMiners,
+    );
 }
 
 #[query]
@@ -66,12 +87,10 @@ fn get_wasm_len() -> usize {
 
 #[query]
 fn filter_out_known_index(indices: Vec<u64>) -> Vec<u64> {
-    read_state(|s| {
-        indices
-            .into_iter()
-            .filter(|index| !s.miner_block_index.contains(index) && !is_known_block(*index))
-            .collect()
-    })
+    indices
+        .into_iter()
+        .filter(|index| !is_known_block(*index))
+        .collect()
 }
 
 #[query]
@@ -127,6 +146,63 @@ fn get_current_block_status() -> CurrentBlockStatus {
     })
 }
 
+#[derive(CandidType)]
+struct RoundParticipant {
+    miner: Principal,
+    owner: Option<Principal>,
+    burned_cycles: u64,
+    /// Burned cycles times the multiplier earned by locking BOB.
+    effective_weight: u64,
+    win_probability: f64,
+}
+
+#[derive(CandidType)]
+struct CurrentRound {
+    round: u64,
+    started_at: u64,
+    /// Time at which the round is drawn, `None` while the draw is running.
+    deadline: Option<u64>,
+    total_burned_cycles: u64,
+    /// Cycles burned for the pool members when the round is drawn.
+    pending_pool_burn: u64,
+    participants: Vec<RoundParticipant>,
+}
+
+#[query]
+fn get_current_round() -> CurrentRound {
+    let pending_pool_burn = pending_pool_burn();
+    let now = ic_cdk::api::time();
+    read_state(|s| {
+        // Denied miners have no weight.
+        let weights: BTreeMap<Principal, u64> = s.effective_weights(now).into_iter().collect();
+        let probabilities = s.win_probabilities(pending_pool_burn, now);
+        CurrentRound {
+            round: current_round(),
+            started_at: last_solved_challenge_ts(),
+            deadline: get_deadline(TaskType::ProcessLogic),
+            total_burned_cycles: s.miner_to_burned_cycles.values().sum(),
+            pending_pool_burn,
+            participants: s
+                .miner_to_burned_cycles
+                .iter()
+                .map(|(miner, burned_cycles)| RoundParticipant {
+                    miner: *miner,
+                    owner: get_miner_owner(*miner),
+                    burned_cycles: *burned_cycles,
+                    effective_weight: weights.get(miner).copied().unwrap_or(0),
+                    win_probability: probabilities.get(miner).copied().unwrap_or(0.0),
+                })
+                .collect(),
+        }
+    })
+}
+
+#[query]
+fn get_win_probability(miner: Principal) -> f64 {
+    let pending_pool_burn = pending_pool_burn();
+    read_state(|s| s.win_probability(miner, pending_pool_burn, ic_cdk::api::time()))
+}
+
 #[derive(CandidType, Ord, PartialOrd, Eq, PartialEq, Clone)]
 struct LeaderBoardEntry {
     block_count: u64,
@@ -138,19 +214,14 @@ struct LeaderBoardEntry {
 fn get_leader_board() -> Vec<LeaderBoardEntry> {
     use std::collections::BTreeSet;
     let mut result: BTreeSet<LeaderBoardEntry> = Default::default();
-    read_state(|s| {
-        for (owner, miners) in s.principal_to_miner.iter() {
-            let mined_blocks: u64 = miners
-                .iter()
-                .map(|m| s.miner_to_mined_block.get(m).unwrap_or(&0))
-                .sum();
-            result.insert(LeaderBoardEntry {
-                block_count: mined_blocks,
-                miner_count: miners.len(),
-                owner: *owner,
-            });
-        }
-    });
+    for (owner, miners) in get_principal_to_miner() {
+        let mined_blocks: u64 = miners.iter().map(|m| get_mined_block_count_of(*m)).sum();
+        result.insert(LeaderBoardEntry {
+            block_count: mined_blocks,
+            miner_count: miners.len(),
+            owner,
+        });
+    }
     result.iter().rev().take(20).cloned().collect()
 }
 
@@ -161,66 +232,19 @@ async fn spawn_miner(block_index: u64) -> Result<Principal, String> {
     if ic_cdk::caller() == Principal::anonymous() {
         return Err("cannot spawn anonymously".to_string());
     }
+    if is_paused(Subsystem::Spawning) {
+        return Err("spawning miners is paused".to_string());
+    }
+    mutate_state(|s| {
+        s.rate_limiter
+            .try_acquire(ic_cdk::caller(), ic_cdk::api::time())
+    })?;
     let _guard_principal = GuardPrincipal::new(ic_cdk::caller())
         .map_err(|guard_error| format!("{:?}", guard_error))?;
 
-    if read_state(|s| s.miner_block_index.contains(&block_index)) || is_known_block(block_index) {
-        return Err("already consumed block index".to_string());
-    }
-
-    let transaction = fetch_block(block_index).await?.transaction;
-
-    if transaction.memo != icp_ledger::Memo(1347768404) {
-        return Err("unknown memo".to_string());
-    }
-
-    let caller = AccountIdentifier::new(ic_types::PrincipalId(ic_cdk::caller()), None);
-    let expect_to = AccountIdentifier::from_hex(
-        "e7b583c3e3e2837c987831a97a6b980cbb0be89819e85915beb3c02006923fce",
-    )
-    .unwrap();
-    let old_to = AccountIdentifier::from_hex(
-        "6b896884e0b42634eca9c68c435c47b0ef2b97cf874a17198856b9c4efe89249",
-    )
-    .unwrap();
-
-    if let Operation::Transfer {
-        from, to, amount, ..
-    } = transaction.operation
-    {
-        assert_eq!(from, caller, "unexpected caller");
-        if to != expect_to && to != old_to {
-            panic!("unexpected destintaion");
-        }
-        assert!(
-            amount >= icp_ledger::Tokens::from_e8s(99_990_000_u64),
-            "unexpected amount"
-        );
-    } else {
-        return Err("expected transfer".to_string());
-    }
-
-    const CYCLES_FOR_CREATION: u64 = 2_500_000_000_000;
-
-    let _res = notify_top_up(block_index).await?;
-
-    let arg = Encode!(&ic_cdk::caller()).unwrap();
-
-    let canister_id = create_canister(CYCLES_FOR_CREATION)
+    payment::spawn_miner(&CdkRuntime, ic_cdk::caller(), block_index)
         .await
-        .map_err(|e| format!("{} - {:?}", e.method, e.reason))?;
-
-    install_code(canister_id, miner_wasm().to_vec(), arg)
-        .await
-        .map_err(|e| format!("{} - {:?}", e.method, e.reason))?;
-
-    mutate_state(|s| {
-        s.new_miner(canister_id, ic_cdk::caller(), block_index);
-    });
-
-    insert_new_miner(canister_id, ic_cdk::caller(), block_index);
-
-    Ok(canister_id)
+        .map_err(|e| e.to_string())
 }
 
 #[update]
@@ -228,70 +252,156 @@ async fn join_pool(block_index: u64) -> Result<(), String> {
     if ic_cdk::caller() == Principal::anonymous() {
         return Err("cannot spawn anonymously".to_string());
     }
+    if is_paused(Subsystem::PoolJoins) {
+        return Err("joining the pool is paused".to_string());
+    }
+    mutate_state(|s| {
+        s.rate_limiter
+            .try_acquire(ic_cdk::caller(), ic_cdk::api::time())
+    })?;
     let _guard_principal = GuardPrincipal::new(ic_cdk::caller())
         .map_err(|guard_error| format!("{:?}", guard_error))?;
 
-    if read_state(|s| s.miner_block_index.contains(&block_index)) || is_known_block(block_index) {
-        return Err("already consumed block index".to_string());
-    }
-
-    let transaction = fetch_block(block_index).await?.transaction;
+    payment::join_pool(&CdkRuntime, ic_cdk::caller(), block_index)
+        .await
+        .map_err(|e| e.to_string())
+}
 
-    if transaction.memo != icp_ledger::Memo(1347768404) {
-        return Err("unknown memo".to_string());
+/// Spawns a miner paid with the cycles attached to the call, which must
+/// cover the creation cost. All the attached cycles fund the new miner.
+#[update]
+async fn spawn_miner_with_cycles() -> Result<Principal, String> {
+    let caller = ic_cdk::caller();
+    if is_paused(Subsystem::Spawning) {
+        return Err("spawning miners is paused".to_string());
     }
+    mutate_state(|s| s.rate_limiter.try_acquire(caller, ic_cdk::api::time()))?;
+    let _guard_principal =
+        GuardPrincipal::new(caller).map_err(|guard_error| format!("{:?}", guard_error))?;
 
-    let caller = AccountIdentifier::new(ic_types::PrincipalId(ic_cdk::caller()), None);
-    let expect_to = AccountIdentifier::from_hex(
-        "e7b583c3e3e2837c987831a97a6b980cbb0be89819e85915beb3c02006923fce",
-    )
-    .unwrap();
+    payment::spawn_miner_with_cycles(&CdkRuntime, caller)
+        .await
+        .map_err(|e| e.to_string())
+}
 
-    if let Operation::Transfer {
-        from, to, amount, ..
-    } = transaction.operation
-    {
-        assert_eq!(from, caller, "unexpected caller");
-        if to != expect_to {
-            panic!("unexpected destintaion");
-        }
-        assert!(
-            amount >= icp_ledger::Tokens::from_e8s(99_990_000_u64),
-            "amount too low"
-        );
+/// Joins the pool for one day per `CYCLES_PER_POOL_DAY` cycles attached to
+/// the call, returns the new expiration of the membership.
+#[update]
+fn join_pool_with_cycles() -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    if is_paused(Subsystem::PoolJoins) {
+        return Err("joining the pool is paused".to_string());
+    }
+    let _guard_principal =
+        GuardPrincipal::new(caller).map_err(|guard_error| format!("{:?}", guard_error))?;
 
-        let _res = notify_top_up(block_index).await?;
+    payment::join_pool_with_cycles(&CdkRuntime, caller).map_err(|e| e.to_string())
+}
 
-        let caller = ic_cdk::caller();
-        let from_time = if let Some(time) = get_expiration(caller) {
-            time
-        } else {
-            ic_cdk::api::time()
-        };
-        let days = amount.get_e8s() / 100_000_000;
-        let expire_at = from_time + days * DAY_NANOS;
-        insert_expiration(caller, expire_at);
-        insert_block_index(block_index);
-        Ok(())
-    } else {
-        Err("expected transfer".to_string())
+/// Queues a payment whose block is not available yet, the minter spawns
+/// the miner or extends the membership once the block appears.
+#[update]
+fn queue_payment(block_index: u64, kind: PaymentKind) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("cannot pay anonymously".to_string());
+    }
+    let subsystem = kind.subsystem();
+    if is_paused(subsystem) {
+        return Err(format!("{subsystem:?} is paused"));
     }
+    mutate_state(|s| s.rate_limiter.try_acquire(caller, ic_cdk::api::time()))?;
+    payment::queue_payment(caller, block_index, kind, ic_cdk::api::time())?;
+    log!(
+        INFO,
+        "[queue_payment] {caller} queued block {block_index} to {kind:?}"
+    );
+    schedule_after(Duration::from_secs(10), TaskType::CompletePayments);
+    Ok(())
+}
+
+#[query]
+fn get_pending_payments(maybe_owner: Option<Principal>) -> Vec<(u64, PendingPayment)> {
+    bob_minter_v2::memory::get_pending_payments()
+        .into_iter()
+        .filter(|(_, payment)| maybe_owner.map_or(true, |owner| payment.owner == owner))
+        .collect()
 }
 
 #[update]
 async fn upgrade_miner(miner: Principal) -> Result<(), String> {
     if let Some(owner) = get_miner_owner(miner) {
         assert_eq!(ic_cdk::caller(), owner);
+        mutate_state(|s| s.rate_limiter.try_acquire(owner, ic_cdk::api::time()))?;
         stop_canister(miner).await.map_err(|e| format!("{e:?}"))?;
         reinstall_code(miner, miner_wasm().to_vec(), Encode!(&owner).unwrap())
             .await
             .map_err(|e| format!("{e:?}"))?;
         start_canister(miner).await.map_err(|e| format!("{e:?}"))?;
+        record_event(EventType::MinerUpgraded { miner }, ic_cdk::api::time());
         return Ok(());
     }
     Err("unknown miner".to_string())
 }
 
+/// Status of a miner, only its owner can call it since the minter is the
+/// only controller of the miners it spawns.
+#[update]
+async fn get_miner_status(miner: Principal) -> Result<MinerStatus, String> {
+    match get_miner_owner(miner) {
+        Some(owner) if owner == ic_cdk::caller() => {}
+        Some(_) => return Err("only the owner can get the status of a miner".to_string()),
+        None => return Err("unknown miner".to_string()),
+    }
+    canister_status(miner).await.map_err(|e| format!("{e:?}"))
+}
+
+/// Rejects ingress messages that would fail anyway before they are charged
+/// and executed. Inter-canister calls are not inspected, so every update still
+/// performs its own checks.
+#[inspect_message]
+fn inspect_message() {
+    let method = ic_cdk::api::call::method_name();
+    let caller = ic_cdk::caller();
+
+    let accept = match method.as_str() {
+        "enable_auto_renewal"
+        | "cancel_auto_renewal"
+        | "set_claim_mode"
+        | "claim_rewards"
+        | "lock_bob"
+        | "unlock_bob"
+        | "claim_vested"
+        | "get_miner_status" => caller != Principal::anonymous(),
+        "spawn_miner" | "join_pool" | "upgrade_miner" | "queue_payment" => {
+            caller != Principal::anonymous()
+                && read_state(|s| s.rate_limiter.has_capacity(&caller, ic_cdk::api::time()))
+        }
+        "submit_burned_cycles" => get_miner_owner(caller).is_some() && !is_denied_miner(caller),
+        // Cycles can only be attached by canisters.
+        "submit_cycles" | "spawn_miner_with_cycles" | "join_pool_with_cycles" => false,
+        "add_admin"
+        | "remove_admin"
+        | "prepare_snapshot"
+        | "begin_snapshot_import"
+        | "upload_snapshot_chunk"
+        | "finish_snapshot_import"
+        | "set_vesting_params" => bob_minter_v2::admin::is_controller(&caller),
+        "pause"
+        | "resume"
+        | "set_pool_payout_concurrency"
+        | "deny"
+        | "allow"
+        | "register_reward_stream"
+        | "stop_reward_stream" => bob_minter_v2::admin::is_controller_or_admin(&caller),
+        _ => true,
+    };
+
+    if accept {
+        ic_cdk::api::call::accept_message();
+    }
+}
+
 #[export_name = "canister_global_timer"]
 fn timer() {
     bob_minter_v2::timer();
@@ -302,13 +412,22 @@ fn submit_burned_cycles(cycles: u64) -> Result<(), String> {
     let _guard_principal = GuardPrincipal::new(ic_cdk::caller())
         .map_err(|guard_error| format!("{:?}", guard_error))?;
 
-    if !read_state(|s| s.miner_to_owner.contains_key(&ic_cdk::caller())) {
+    if !is_miner(ic_cdk::caller()) {
+        log!(
+            DEBUG,
+            "[submit_burned_cycles] rejected submission from unknown miner {}",
+            ic_cdk::caller()
+        );
         return Err(
             "Unregitered miner, only miner spawned from this canister are allowed to submit"
                 .to_string(),
         );
     }
 
+    if is_denied_miner(ic_cdk::caller()) {
+        return Err("this miner is excluded from the lottery".to_string());
+    }
+
     if cycles < 1_000_000_000 {
         return Err("Not enough cycle burned".to_string());
     }
@@ -321,37 +440,310 @@ fn submit_burned_cycles(cycles: u64) -> Result<(), String> {
             .and_modify(|e| *e += cycles)
             .or_insert(cycles);
     });
+    record_miner_submission(caller, cycles, ic_cdk::api::time());
+    record_event(
+        EventType::CyclesSubmitted {
+            miner: caller,
+            cycles,
+        },
+        ic_cdk::api::time(),
+    );
+
+    log!(
+        DEBUG,
+        "[submit_burned_cycles] {caller} submitted {cycles} burned cycles"
+    );
 
     Ok(())
 }
 
+/// Trustless alternative to `submit_burned_cycles`: the cycles attached to
+/// the call are accepted and burned by the minter, so any canister can mine
+/// without being spawned by this minter. Returns the burned amount.
+#[update]
+fn submit_cycles() -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    let _guard_principal =
+        GuardPrincipal::new(caller).map_err(|guard_error| format!("{:?}", guard_error))?;
+
+    // The attached cycles are refunded since they are not accepted.
+    if is_denied_miner(caller) {
+        return Err("this miner is excluded from the lottery".to_string());
+    }
+
+    let available = ic_cdk::api::call::msg_cycles_available128();
+    if available < 1_000_000_000 {
+        return Err("Not enough cycles attached".to_string());
+    }
+
+    let accepted = ic_cdk::api::call::msg_cycles_accept128(available);
+    let cycles = ic_cdk::api::cycles_burn(accepted).min(u64::MAX as u128) as u64;
+
+    mutate_state(|s| {
+        s.miner_to_burned_cycles
+            .entry(caller)
+            .and_modify(|e| *e = e.saturating_add(cycles))
+            .or_insert(cycles);
+    });
+    record_miner_submission(caller, cycles, ic_cdk::api::time());
+    record_event(
+        EventType::CyclesSubmitted {
+            miner: caller,
+            cycles,
+        },
+        ic_cdk::api::time(),
+    );
+
+    log!(
+        DEBUG,
+        "[submit_cycles] {caller} attached and burned {cycles} cycles"
+    );
+
+    Ok(cycles)
+}
+
 #[query]
 fn get_statistics() -> Stats {
     read_state(|s| Stats {
-        average_block_speed: 0,
+        average_block_speed: average_block_speed(),
         block_count: s.total_blocks_mined(),
-        miner_count: s.miner_to_owner.keys().len(),
+        miner_count: miner_count() as usize,
         halving_count: s.total_blocks_mined() / BLOCK_HALVING,
         cycle_balance: ic_cdk::api::canister_balance(),
         time_since_last_block: s.time_since_last_block(),
         pending_blocks: get_block_to_mine(),
+        pool_payout: get_pool_payout_job(),
+        pool_payout_concurrency: get_pool_payout_concurrency(),
     })
 }
 
+#[query]
+fn get_emission_info() -> EmissionInfo {
+    let average_block_speed = average_block_speed();
+    read_state(|s| s.emission_info(average_block_speed))
+}
+
 #[derive(CandidType)]
 struct PoolStats {
     pool_mined_blocks: u64,
     users_count_in_pool: u64,
 }
 
+/// Switches between transferring the rewards of the caller as they are
+/// mined and crediting them to a balance withdrawn with `claim_rewards`.
+#[update]
+fn set_claim_mode(enabled: bool) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("anonymous principals cannot receive rewards".to_string());
+    }
+    bob_minter_v2::memory::set_claim_mode(caller, enabled);
+    log!(
+        INFO,
+        "[set_claim_mode] {caller} set claim mode to {enabled}"
+    );
+    Ok(())
+}
+
+#[query]
+fn get_claim_mode(owner: Principal) -> bool {
+    is_claim_mode(owner)
+}
+
+#[update]
+async fn claim_rewards(to: Account) -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("cannot claim anonymously".to_string());
+    }
+    let _guard_principal =
+        GuardPrincipal::new(caller).map_err(|guard_error| format!("{:?}", guard_error))?;
+
+    bob_minter_v2::claim_rewards(&CdkRuntime, caller, to).await
+}
+
+#[query]
+fn get_claimable(owner: Principal) -> u64 {
+    bob_minter_v2::memory::get_claimable(owner)
+}
+
+/// Locks BOB of the caller, approved beforehand, to boost the lottery weight
+/// of its miners until the lock expires.
+#[update]
+async fn lock_bob(amount_e8s: u64, days: u64) -> Result<BobLock, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("cannot lock anonymously".to_string());
+    }
+    let _guard_principal =
+        GuardPrincipal::new(caller).map_err(|guard_error| format!("{:?}", guard_error))?;
+
+    locks::lock_bob(&CdkRuntime, ic_cdk::id(), caller, amount_e8s, days).await
+}
+
+/// Returns the BOB locked by the caller once the lock expired.
+#[update]
+async fn unlock_bob() -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    let _guard_principal =
+        GuardPrincipal::new(caller).map_err(|guard_error| format!("{:?}", guard_error))?;
+
+    locks::unlock_bob(&CdkRuntime, caller).await
+}
+
+/// Estimates what `amount_e8s` buys at the current conversion rate of the
+/// cycles minting canister, fetched if the cached one is stale.
+#[update]
+async fn estimate_costs(amount_e8s: u64) -> Result<CostEstimate, String> {
+    let rate = estimator::refresh_conversion_rate(&CdkRuntime).await?;
+    Ok(estimator::estimate(amount_e8s, &rate))
+}
+
+/// Same as `estimate_costs` with the cached conversion rate.
+#[query]
+fn get_cost_estimate(amount_e8s: u64) -> Result<CostEstimate, String> {
+    let cached = read_state(|s| s.conversion_rate.clone())
+        .ok_or_else(|| "no conversion rate cached yet, call estimate_costs".to_string())?;
+    Ok(estimator::estimate(amount_e8s, &cached.rate))
+}
+
+/// Transfers the vested rewards of the caller.
+#[update]
+async fn claim_vested() -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    let _guard_principal =
+        GuardPrincipal::new(caller).map_err(|guard_error| format!("{:?}", guard_error))?;
+
+    vesting::release_vested(&CdkRuntime, caller).await
+}
+
+#[query]
+fn get_vesting_schedules(owner: Option<Principal>) -> Vec<VestingSchedule> {
+    get_vesting_schedules_of(owner.unwrap_or_else(ic_cdk::caller))
+}
+
+#[query]
+fn get_vesting_params() -> VestingParams {
+    bob_minter_v2::memory::get_vesting_params()
+}
+
+/// Sets the fraction of the block rewards that vests and the vesting
+/// period. Controlled by the DAO, only applies to the blocks paid after.
+#[update]
+fn set_vesting_params(params: VestingParams) -> Result<(), String> {
+    let caller = ensure_controller()?;
+    params.validate()?;
+    bob_minter_v2::memory::set_vesting_params(params.clone());
+    record_admin_action(caller, AdminAction::SetVestingParams(params.clone()));
+    log!(
+        INFO,
+        "[set_vesting_params] {caller} set the vesting to {} bps over {} days",
+        params.vested_bps,
+        params.duration_days
+    );
+    Ok(())
+}
+
+#[query]
+fn get_bob_lock(owner: Option<Principal>) -> Option<BobLock> {
+    bob_minter_v2::memory::get_bob_lock(owner.unwrap_or_else(ic_cdk::caller))
+}
+
 #[query]
 fn get_pool_statistic() -> PoolStats {
     let pool_id = Principal::from_text("zje3u-qaaaa-aaaai-acr2a-cai").unwrap();
 
-    read_state(|s| PoolStats {
-        pool_mined_blocks: *s.miner_to_mined_block.get(&pool_id).unwrap_or(&0),
+    PoolStats {
+        pool_mined_blocks: get_mined_block_count_of(pool_id),
         users_count_in_pool: user_count(),
-    })
+    }
+}
+
+/// Opts into the automatic renewal of the caller's pool membership, the
+/// caller must already be a pool member. The caller must approve an ICP
+/// allowance for the minter covering the period (1 ICP per day) plus the
+/// ledger fee.
+#[update]
+fn enable_auto_renewal(period_days: Option<u64>) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("cannot renew anonymously".to_string());
+    }
+    bob_minter_v2::renewal::enable_auto_renewal(
+        caller,
+        period_days.unwrap_or(1),
+        ic_cdk::api::time(),
+    )?;
+    log!(INFO, "[enable_auto_renewal] {caller} enabled auto-renewal");
+    Ok(())
+}
+
+#[update]
+fn cancel_auto_renewal() -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    bob_minter_v2::renewal::cancel_auto_renewal(caller, ic_cdk::api::time())?;
+    log!(
+        INFO,
+        "[cancel_auto_renewal] {caller} cancelled auto-renewal"
+    );
+    Ok(())
+}
+
+#[query]
+fn get_auto_renewal(maybe_target: Option<Principal>) -> Option<AutoRenewal> {
+    bob_minter_v2::memory::get_auto_renewal(maybe_target.unwrap_or(ic_cdk::caller()))
+}
+
+#[query]
+fn get_renewal_history(start: u64, length: u64) -> Vec<RenewalRecord> {
+    const MAX_ENTRIES_PER_RESPONSE: u64 = 1000;
+    let end = start
+        .saturating_add(length.min(MAX_ENTRIES_PER_RESPONSE))
+        .min(bob_minter_v2::memory::renewal_log_len());
+    (start..end)
+        .filter_map(bob_minter_v2::memory::get_renewal_record)
+        .collect()
+}
+
+#[derive(CandidType)]
+struct PoolMember {
+    pub id: Principal,
+    pub expiration: u64,
+    pub amount_paid_e8s: u64,
+    pub cycles_paid: u64,
+    pub rewards_earned: u64,
+}
+
+fn pool_member(id: Principal, expiration: u64) -> PoolMember {
+    let stats = get_pool_member_stats(id);
+    PoolMember {
+        id,
+        expiration,
+        amount_paid_e8s: stats.amount_paid_e8s,
+        cycles_paid: stats.cycles_paid,
+        rewards_earned: stats.rewards_earned,
+    }
+}
+
+#[query]
+fn get_pool_members(start: u64, length: u64) -> Vec<PoolMember> {
+    const MAX_MEMBERS_PER_RESPONSE: u64 = 1000;
+    get_expire_map_page(start, length.min(MAX_MEMBERS_PER_RESPONSE))
+        .into_iter()
+        .map(|(id, expiration)| pool_member(id, expiration))
+        .collect()
+}
+
+/// Lists the active members whose membership expires within `within_secs`.
+#[query]
+fn get_expiring_members(within_secs: u64) -> Vec<PoolMember> {
+    let now = ic_cdk::api::time();
+    let deadline = now.saturating_add(within_secs.saturating_mul(SEC_NANOS));
+    get_expire_map()
+        .into_iter()
+        .filter(|(_, expiration)| *expiration > now && *expiration <= deadline)
+        .map(|(id, expiration)| pool_member(id, expiration))
+        .collect()
 }
 
 #[query]
@@ -366,26 +758,391 @@ fn hours_left_in_pool(maybe_target: Option<Principal>) -> u64 {
 struct Miner {
     pub id: Principal,
     pub mined_blocks: u64,
+    pub last_submission_ts: Option<u64>,
+    pub total_cycles_submitted: u64,
+    pub consecutive_missed_rounds: Option<u64>,
 }
 
 #[query]
 fn get_miners(of: Principal) -> Vec<Miner> {
-    read_state(|s| {
-        let miners = s
-            .principal_to_miner
-            .get(&of)
-            .cloned()
-            .unwrap_or_else(Vec::new);
-        let mut result: Vec<Miner> = vec![];
-        for miner in miners {
-            let mined_blocks = *s.miner_to_mined_block.get(&miner).unwrap_or(&0);
-            result.push(Miner {
+    let current_round = current_round();
+    get_miners_of(of)
+        .into_iter()
+        .map(|miner| {
+            let stats = get_miner_stats(miner);
+            Miner {
+                id: miner,
+                mined_blocks: get_mined_block_count_of(miner),
+                last_submission_ts: stats.as_ref().and_then(|st| st.last_submission_ts),
+                total_cycles_submitted: stats
+                    .as_ref()
+                    .map(|st| st.total_cycles_submitted)
+                    .unwrap_or(0),
+                consecutive_missed_rounds: stats
+                    .map(|st| st.consecutive_missed_rounds(current_round)),
+            }
+        })
+        .collect()
+}
+
+#[derive(CandidType)]
+struct InactiveMiner {
+    pub id: Principal,
+    pub owner: Principal,
+    pub last_submission_ts: Option<u64>,
+    pub consecutive_missed_rounds: Option<u64>,
+}
+
+/// Lists the miners that missed at least `min_missed_rounds` consecutive
+/// rounds. Miners without any submission since liveness tracking started are
+/// reported without a missed round count.
+#[query]
+fn get_inactive_miners(min_missed_rounds: u64) -> Vec<InactiveMiner> {
+    let pool_id = Principal::from_text("zje3u-qaaaa-aaaai-acr2a-cai").unwrap();
+    let current_round = current_round();
+    get_miner_to_owner_and_index()
+        .into_iter()
+        .filter(|(miner, _)| *miner != pool_id)
+        .filter_map(|(miner, (owner, _))| {
+            let stats = get_miner_stats(miner);
+            let consecutive_missed_rounds = stats
+                .as_ref()
+                .map(|st| st.consecutive_missed_rounds(current_round));
+            if consecutive_missed_rounds.unwrap_or(u64::MAX) < min_missed_rounds {
+                return None;
+            }
+            Some(InactiveMiner {
                 id: miner,
-                mined_blocks,
-            });
+                owner,
+                last_submission_ts: stats.and_then(|st| st.last_submission_ts),
+                consecutive_missed_rounds,
+            })
+        })
+        .collect()
+}
+
+#[update]
+fn add_admin(admin: Principal) -> Result<(), String> {
+    let caller = ensure_controller()?;
+    if !insert_admin(admin) {
+        return Err("already an admin".to_string());
+    }
+    record_admin_action(caller, AdminAction::AddAdmin(admin));
+    log!(INFO, "[add_admin] {caller} added admin {admin}");
+    Ok(())
+}
+
+#[update]
+fn remove_admin(admin: Principal) -> Result<(), String> {
+    let caller = ensure_controller()?;
+    if !bob_minter_v2::memory::remove_admin(admin) {
+        return Err("not an admin".to_string());
+    }
+    record_admin_action(caller, AdminAction::RemoveAdmin(admin));
+    log!(INFO, "[remove_admin] {caller} removed admin {admin}");
+    Ok(())
+}
+
+#[update]
+fn pause(subsystem: Subsystem) -> Result<(), String> {
+    let caller = ensure_controller_or_admin()?;
+    if !pause_subsystem(subsystem) {
+        return Err("already paused".to_string());
+    }
+    record_admin_action(caller, AdminAction::Pause(subsystem));
+    log!(INFO, "[pause] {caller} paused {subsystem:?}");
+    Ok(())
+}
+
+#[update]
+fn resume(subsystem: Subsystem) -> Result<(), String> {
+    let caller = ensure_controller_or_admin()?;
+    if !resume_subsystem(subsystem) {
+        return Err("not paused".to_string());
+    }
+    record_admin_action(caller, AdminAction::Resume(subsystem));
+    log!(INFO, "[resume] {caller} resumed {subsystem:?}");
+    if subsystem == Subsystem::Payouts {
+        schedule_now(TaskType::MineBob);
+    }
+    Ok(())
+}
+
+/// Sets the number of pool members paid concurrently.
+#[update]
+fn set_pool_payout_concurrency(concurrency: u64) -> Result<(), String> {
+    let caller = ensure_controller_or_admin()?;
+    if concurrency == 0 || concurrency > MAX_POOL_PAYOUT_CONCURRENCY {
+        return Err(format!(
+            "the concurrency must be between 1 and {MAX_POOL_PAYOUT_CONCURRENCY}"
+        ));
+    }
+    bob_minter_v2::memory::set_pool_payout_concurrency(concurrency);
+    record_admin_action(caller, AdminAction::SetPoolPayoutConcurrency(concurrency));
+    log!(
+        INFO,
+        "[set_pool_payout_concurrency] {caller} set the pool payout concurrency to {concurrency}"
+    );
+    Ok(())
+}
+
+/// Excludes a miner, or all the miners of an owner, from the lottery.
+#[update]
+fn deny(principal: Principal, reason: String) -> Result<(), String> {
+    let caller = ensure_controller_or_admin()?;
+    if !insert_deny_list_entry(DenyListEntry {
+        principal,
+        reason: reason.clone(),
+        added_at: ic_cdk::api::time(),
+        added_by: caller,
+    }) {
+        return Err("already denied".to_string());
+    }
+    record_admin_action(caller, AdminAction::Deny { principal, reason });
+    log!(INFO, "[deny] {caller} denied {principal}");
+    Ok(())
+}
+
+#[update]
+fn allow(principal: Principal) -> Result<(), String> {
+    let caller = ensure_controller_or_admin()?;
+    if !remove_deny_list_entry(principal) {
+        return Err("not denied".to_string());
+    }
+    record_admin_action(caller, AdminAction::Allow(principal));
+    log!(INFO, "[allow] {caller} allowed {principal}");
+    Ok(())
+}
+
+#[query]
+fn get_deny_list() -> Vec<DenyListEntry> {
+    bob_minter_v2::memory::get_deny_list()
+}
+
+/// Registers a partner token paid to the winner of every block, the
+/// minter must hold the budget on the partner ledger.
+#[update]
+fn register_reward_stream(args: RewardStreamArgs) -> Result<u64, String> {
+    let caller = ensure_controller_or_admin()?;
+    args.validate()?;
+    let id = insert_reward_stream(RewardStream {
+        ledger_id: args.ledger_id,
+        amount_per_block: args.amount_per_block,
+        budget: args.budget,
+        spent: 0,
+        start_block: args.start_block,
+        end_block: args.end_block,
+        registered_at: ic_cdk::api::time(),
+    });
+    record_admin_action(caller, AdminAction::RegisterRewardStream(id));
+    log!(
+        INFO,
+        "[register_reward_stream] {caller} registered stream {id} of {} per block on {}",
+        args.amount_per_block,
+        args.ledger_id
+    );
+    Ok(id)
+}
+
+/// Ends a reward stream at the current block height.
+#[update]
+fn stop_reward_stream(id: u64) -> Result<(), String> {
+    let caller = ensure_controller_or_admin()?;
+    let mut stream = get_reward_stream(id).ok_or_else(|| "unknown stream".to_string())?;
+    let height = read_state(|s| s.total_blocks_mined());
+    if stream
+        .end_block
+        .is_some_and(|end_block| end_block <= height)
+    {
+        return Err("stream already ended".to_string());
+    }
+    stream.end_block = Some(height);
+    update_reward_stream(id, stream);
+    record_admin_action(caller, AdminAction::StopRewardStream(id));
+    log!(INFO, "[stop_reward_stream] {caller} stopped stream {id}");
+    Ok(())
+}
+
+#[query]
+fn get_reward_streams() -> Vec<(u64, RewardStream)> {
+    bob_minter_v2::memory::get_reward_streams()
+}
+
+#[query]
+fn get_admins() -> Vec<Principal> {
+    bob_minter_v2::memory::get_admins()
+}
+
+#[query]
+fn get_paused_subsystems() -> Vec<Subsystem> {
+    bob_minter_v2::memory::get_paused_subsystems()
+}
+
+#[query]
+fn get_audit_log(start: u64, length: u64) -> Vec<AuditEntry> {
+    const MAX_ENTRIES_PER_RESPONSE: u64 = 1000;
+    let end = start
+        .saturating_add(length.min(MAX_ENTRIES_PER_RESPONSE))
+        .min(audit_log_len());
+    (start..end).filter_map(get_audit_entry).collect()
+}
+
+#[query]
+fn get_events(start: u64, length: u64) -> Vec<Event> {
+    const MAX_EVENTS_PER_RESPONSE: u64 = 1000;
+    let end = start
+        .saturating_add(length.min(MAX_EVENTS_PER_RESPONSE))
+        .min(event_count());
+    (start..end).filter_map(get_event).collect()
+}
+
+#[query]
+fn get_reconciliation_report() -> ReconciliationState {
+    get_reconciliation_state()
+}
+
+#[query]
+fn get_discrepancies(start: u64, length: u64) -> Vec<DiscrepancyRecord> {
+    const MAX_ENTRIES_PER_RESPONSE: u64 = 1000;
+    let end = start
+        .saturating_add(length.min(MAX_ENTRIES_PER_RESPONSE))
+        .min(discrepancy_count());
+    (start..end).filter_map(get_discrepancy).collect()
+}
+
+#[query(hidden = true)]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    if ic_cdk::api::in_replicated_execution() {
+        ic_cdk::trap("update call rejected");
+    }
+
+    if req.path() == "/metrics" {
+        let mut writer = ic_metrics_encoder::MetricsEncoder::new(
+            vec![],
+            (ic_cdk::api::time() / 1_000_000) as i64,
+        );
+
+        match bob_minter_v2::metrics::encode_metrics(&mut writer) {
+            Ok(()) => HttpResponseBuilder::ok()
+                .header("Content-Type", "text/plain; version=0.0.4")
+                .with_body_and_content_length(writer.into_inner())
+                .build(),
+            Err(err) => {
+                HttpResponseBuilder::server_error(format!("Failed to encode metrics: {}", err))
+                    .build()
+            }
         }
-        result
-    })
+    } else if req.path() == "/logs" {
+        use bob_minter_v2::logs::{Log, Priority, Sort};
+        use std::str::FromStr;
+
+        let max_skip_timestamp = match req.raw_query_param("time") {
+            Some(arg) => match u64::from_str(arg) {
+                Ok(value) => value,
+                Err(_) => {
+                    return HttpResponseBuilder::bad_request()
+                        .with_body_and_content_length("failed to parse the 'time' parameter")
+                        .build();
+                }
+            },
+            None => 0,
+        };
+
+        let mut log: Log = Default::default();
+
+        match req.raw_query_param("priority") {
+            Some(priority_str) => match Priority::from_str(priority_str) {
+                Ok(priority) => match priority {
+                    Priority::Info => log.push_logs(Priority::Info),
+                    Priority::Debug => log.push_logs(Priority::Debug),
+                },
+                Err(_) => log.push_all(),
+            },
+            None => log.push_all(),
+        }
+
+        log.entries
+            .retain(|entry| entry.timestamp >= max_skip_timestamp);
+
+        fn ordering_from_query_params(sort: Option<&str>, max_skip_timestamp: u64) -> Sort {
+            match sort {
+                Some(ord_str) => match Sort::from_str(ord_str) {
+                    Ok(order) => order,
+                    Err(_) => {
+                        if max_skip_timestamp == 0 {
+                            Sort::Ascending
+                        } else {
+                            Sort::Descending
+                        }
+                    }
+                },
+                None => {
+                    if max_skip_timestamp == 0 {
+                        Sort::Ascending
+                    } else {
+                        Sort::Descending
+                    }
+                }
+            }
+        }
+
+        log.sort_logs(ordering_from_query_params(
+            req.raw_query_param("sort"),
+            max_skip_timestamp,
+        ));
+
+        const MAX_BODY_SIZE: usize = 3_000_000;
+        HttpResponseBuilder::ok()
+            .header("Content-Type", "application/json; charset=utf-8")
+            .with_body_and_content_length(log.serialize_logs(MAX_BODY_SIZE))
+            .build()
+    } else {
+        HttpResponseBuilder::not_found().build()
+    }
+}
+
+/// Encodes the stable structures into a snapshot that can then be
+/// downloaded with `get_snapshot_chunk`.
+#[update]
+fn prepare_snapshot() -> Result<SnapshotInfo, String> {
+    ensure_controller()?;
+    Ok(bob_minter_v2::snapshot::prepare_export())
+}
+
+#[query]
+fn get_snapshot_chunk(index: u64) -> Result<Vec<u8>, String> {
+    ensure_controller()?;
+    bob_minter_v2::snapshot::get_export_chunk(index)
+}
+
+#[update]
+fn begin_snapshot_import(info: SnapshotInfo) -> Result<(), String> {
+    let caller = ensure_controller()?;
+    bob_minter_v2::snapshot::begin_import(info.clone())?;
+    log!(
+        INFO,
+        "[begin_snapshot_import] {caller} started importing snapshot {}",
+        info.checksum
+    );
+    Ok(())
+}
+
+#[update]
+fn upload_snapshot_chunk(index: u64, chunk: Vec<u8>) -> Result<(), String> {
+    ensure_controller()?;
+    bob_minter_v2::snapshot::upload_chunk(index, chunk)
+}
+
+#[update]
+fn finish_snapshot_import() -> Result<SnapshotInfo, String> {
+    let caller = ensure_controller()?;
+    let info = bob_minter_v2::snapshot::finish_import()?;
+    log!(
+        INFO,
+        "[finish_snapshot_import] {caller} imported snapshot {}",
+        info.checksum
+    );
+    Ok(info)
 }
 
 #[cfg(test)]
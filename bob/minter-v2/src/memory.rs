@@ -1,9 +1,20 @@
-use crate::Block;
+use crate::admin::{AuditEntry, DenyListEntry, Subsystem};
+use crate::events::Event;
+use crate::locks::BobLock;
+use crate::payment::PendingPayment;
+use crate::payouts::{PoolPayoutJob, DEFAULT_POOL_PAYOUT_CONCURRENCY};
+use crate::reconciliation::{DiscrepancyRecord, Payout, ReconciliationState};
+use crate::renewal::{AutoRenewal, RenewalRecord};
+use crate::streams::{ExtraPayout, RewardStream};
+use crate::vesting::{VestingParams, VestingSchedule};
+use crate::{Block, MinerStats, PoolMemberStats};
 use candid::Principal;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager as MM, VirtualMemory};
 use ic_stable_structures::storable::Bound;
 use ic_stable_structures::DefaultMemoryImpl;
-use ic_stable_structures::{DefaultMemoryImpl as DefMem, StableBTreeMap, StableLog, Storable};
+use ic_stable_structures::{
+    DefaultMemoryImpl as DefMem, StableBTreeMap, StableCell, StableLog, Storable,
+};
 use std::borrow::Cow;
 use std::cell::RefCell;
 
@@ -37,6 +48,52 @@ const LOG_DATA_MEM_ID: MemoryId = MemoryId::new(2);
 const BLOCKS_TO_MINE_ID: MemoryId = MemoryId::new(3);
 const USER_TO_EXPIRATION_ID: MemoryId = MemoryId::new(4);
 const KNOWN_BLOCK_INDEX_ID: MemoryId = MemoryId::new(5);
+const ADMINS_MEM_ID: MemoryId = MemoryId::new(6);
+const PAUSED_SUBSYSTEMS_MEM_ID: MemoryId = MemoryId::new(7);
+const AUDIT_LOG_INDX_MEM_ID: MemoryId = MemoryId::new(8);
+const AUDIT_LOG_DATA_MEM_ID: MemoryId = MemoryId::new(9);
+const MINER_STATS_MEM_ID: MemoryId = MemoryId::new(10);
+const PAYOUTS_MEM_ID: MemoryId = MemoryId::new(11);
+const RECONCILIATION_STATE_MEM_ID: MemoryId = MemoryId::new(12);
+const SEEN_MINTS_MEM_ID: MemoryId = MemoryId::new(13);
+const DISCREPANCIES_INDX_MEM_ID: MemoryId = MemoryId::new(14);
+const DISCREPANCIES_DATA_MEM_ID: MemoryId = MemoryId::new(15);
+const MINER_TO_MINED_BLOCK_MEM_ID: MemoryId = MemoryId::new(16);
+const PRINCIPAL_TO_MINER_MEM_ID: MemoryId = MemoryId::new(17);
+const COUNTERS_MEM_ID: MemoryId = MemoryId::new(18);
+const AUTO_RENEWALS_MEM_ID: MemoryId = MemoryId::new(19);
+const RENEWAL_LOG_INDX_MEM_ID: MemoryId = MemoryId::new(20);
+const RENEWAL_LOG_DATA_MEM_ID: MemoryId = MemoryId::new(21);
+const POOL_MEMBER_STATS_MEM_ID: MemoryId = MemoryId::new(22);
+const EVENT_LOG_INDX_MEM_ID: MemoryId = MemoryId::new(23);
+const EVENT_LOG_DATA_MEM_ID: MemoryId = MemoryId::new(24);
+const CLAIMABLE_MEM_ID: MemoryId = MemoryId::new(25);
+const CLAIM_MODE_MEM_ID: MemoryId = MemoryId::new(26);
+const POOL_PAYOUT_JOB_MEM_ID: MemoryId = MemoryId::new(27);
+const POOL_PAYOUT_QUEUE_MEM_ID: MemoryId = MemoryId::new(28);
+const POOL_PAYOUT_CONCURRENCY_MEM_ID: MemoryId = MemoryId::new(29);
+const PENDING_PAYMENTS_MEM_ID: MemoryId = MemoryId::new(30);
+const DENY_LIST_MEM_ID: MemoryId = MemoryId::new(31);
+const REWARD_STREAMS_MEM_ID: MemoryId = MemoryId::new(32);
+const EXTRA_PAYOUTS_MEM_ID: MemoryId = MemoryId::new(33);
+const BOB_LOCKS_MEM_ID: MemoryId = MemoryId::new(34);
+const VESTING_PARAMS_MEM_ID: MemoryId = MemoryId::new(35);
+const VESTING_SCHEDULES_MEM_ID: MemoryId = MemoryId::new(36);
+const PENDING_MINER_INSTALLS_MEM_ID: MemoryId = MemoryId::new(37);
+const PENDING_TOP_UPS_MEM_ID: MemoryId = MemoryId::new(38);
+
+/// Version of the stable memory layout, bumped whenever the heap state
+/// has to be migrated into new stable structures on upgrade.
+const STABLE_LAYOUT_VERSION: u64 = 1;
+
+/// Counters maintained incrementally so that upgrades do not need to walk
+/// the block log.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+struct Counters {
+    layout_version: u64,
+    blocks_mined: u64,
+    last_solved_challenge_ts: u64,
+}
 
 type VM = VirtualMemory<DefMem>;
 
@@ -72,6 +129,185 @@ thread_local! {
         MEMORY_MANAGER.with(|mm| {
         RefCell::new(StableBTreeMap::init(mm.borrow().get(KNOWN_BLOCK_INDEX_ID)))
         });
+
+    static ADMINS: RefCell<StableBTreeMap<Principal, (), VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(ADMINS_MEM_ID)))
+        });
+
+    static PAUSED_SUBSYSTEMS: RefCell<StableBTreeMap<Cbor<Subsystem>, (), VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(PAUSED_SUBSYSTEMS_MEM_ID)))
+        });
+
+    static AUDIT_LOG: RefCell<StableLog<Cbor<AuditEntry>, VM, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableLog::init(
+            mm.borrow().get(AUDIT_LOG_INDX_MEM_ID),
+            mm.borrow().get(AUDIT_LOG_DATA_MEM_ID),
+        ).expect("failed to initialize the audit log"))
+        });
+
+    static MINER_STATS: RefCell<StableBTreeMap<Principal, Cbor<MinerStats>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(MINER_STATS_MEM_ID)))
+        });
+
+    static PAYOUTS: RefCell<StableBTreeMap<u64, Cbor<Payout>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(PAYOUTS_MEM_ID)))
+        });
+
+    static RECONCILIATION_STATE: RefCell<StableCell<Cbor<ReconciliationState>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableCell::init(
+            mm.borrow().get(RECONCILIATION_STATE_MEM_ID),
+            Cbor(ReconciliationState::default()),
+        ).expect("failed to initialize the reconciliation state"))
+        });
+
+    static SEEN_MINTS: RefCell<StableBTreeMap<Cbor<(u64, Principal)>, u64, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(SEEN_MINTS_MEM_ID)))
+        });
+
+    static DISCREPANCIES: RefCell<StableLog<Cbor<DiscrepancyRecord>, VM, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableLog::init(
+            mm.borrow().get(DISCREPANCIES_INDX_MEM_ID),
+            mm.borrow().get(DISCREPANCIES_DATA_MEM_ID),
+        ).expect("failed to initialize the discrepancy log"))
+        });
+
+    static MINER_TO_MINED_BLOCK: RefCell<StableBTreeMap<Principal, u64, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(MINER_TO_MINED_BLOCK_MEM_ID)))
+        });
+
+    static PRINCIPAL_TO_MINER: RefCell<StableBTreeMap<Principal, Cbor<Vec<Principal>>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(PRINCIPAL_TO_MINER_MEM_ID)))
+        });
+
+    static COUNTERS: RefCell<StableCell<Cbor<Counters>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableCell::init(
+            mm.borrow().get(COUNTERS_MEM_ID),
+            Cbor(Counters::default()),
+        ).expect("failed to initialize the counters"))
+        });
+
+    static AUTO_RENEWALS: RefCell<StableBTreeMap<Principal, Cbor<AutoRenewal>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(AUTO_RENEWALS_MEM_ID)))
+        });
+
+    static RENEWAL_LOG: RefCell<StableLog<Cbor<RenewalRecord>, VM, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableLog::init(
+            mm.borrow().get(RENEWAL_LOG_INDX_MEM_ID),
+            mm.borrow().get(RENEWAL_LOG_DATA_MEM_ID),
+        ).expect("failed to initialize the renewal log"))
+        });
+
+    static POOL_MEMBER_STATS: RefCell<StableBTreeMap<Principal, Cbor<PoolMemberStats>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(POOL_MEMBER_STATS_MEM_ID)))
+        });
+
+    static EVENT_LOG: RefCell<StableLog<Cbor<Event>, VM, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableLog::init(
+            mm.borrow().get(EVENT_LOG_INDX_MEM_ID),
+            mm.borrow().get(EVENT_LOG_DATA_MEM_ID),
+        ).expect("failed to initialize the event log"))
+        });
+
+    static CLAIMABLE: RefCell<StableBTreeMap<Principal, u64, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(CLAIMABLE_MEM_ID)))
+        });
+
+    static CLAIM_MODE: RefCell<StableBTreeMap<Principal, (), VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(CLAIM_MODE_MEM_ID)))
+        });
+
+    static POOL_PAYOUT_JOB: RefCell<StableCell<Cbor<Option<PoolPayoutJob>>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableCell::init(
+            mm.borrow().get(POOL_PAYOUT_JOB_MEM_ID),
+            Cbor(None),
+        ).expect("failed to initialize the pool payout job"))
+        });
+
+    static POOL_PAYOUT_QUEUE: RefCell<StableBTreeMap<Principal, (), VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(POOL_PAYOUT_QUEUE_MEM_ID)))
+        });
+
+    static POOL_PAYOUT_CONCURRENCY: RefCell<StableCell<u64, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableCell::init(
+            mm.borrow().get(POOL_PAYOUT_CONCURRENCY_MEM_ID),
+            DEFAULT_POOL_PAYOUT_CONCURRENCY,
+        ).expect("failed to initialize the pool payout concurrency"))
+        });
+
+    static PENDING_PAYMENTS: RefCell<StableBTreeMap<u64, Cbor<PendingPayment>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(PENDING_PAYMENTS_MEM_ID)))
+        });
+
+    static DENY_LIST: RefCell<StableBTreeMap<Principal, Cbor<DenyListEntry>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(DENY_LIST_MEM_ID)))
+        });
+
+    static REWARD_STREAMS: RefCell<StableBTreeMap<u64, Cbor<RewardStream>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(REWARD_STREAMS_MEM_ID)))
+        });
+
+    /// Secondary rewards waiting to be paid, keyed by block timestamp,
+    /// recipient and ledger.
+    static EXTRA_PAYOUTS: RefCell<StableBTreeMap<Cbor<(u64, Principal, Principal)>, Cbor<ExtraPayout>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(EXTRA_PAYOUTS_MEM_ID)))
+        });
+
+    static BOB_LOCKS: RefCell<StableBTreeMap<Principal, Cbor<BobLock>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(BOB_LOCKS_MEM_ID)))
+        });
+
+    static VESTING_PARAMS: RefCell<StableCell<Cbor<VestingParams>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableCell::init(
+            mm.borrow().get(VESTING_PARAMS_MEM_ID),
+            Cbor(VestingParams::default()),
+        ).expect("failed to initialize the vesting parameters"))
+        });
+
+    /// Vesting schedules keyed by owner and block timestamp.
+    static VESTING_SCHEDULES: RefCell<StableBTreeMap<Cbor<(Principal, u64)>, Cbor<VestingSchedule>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(VESTING_SCHEDULES_MEM_ID)))
+        });
+
+    /// Miners registered whose code still has to be installed, with their
+    /// owner.
+    static PENDING_MINER_INSTALLS: RefCell<StableBTreeMap<Principal, Principal, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(PENDING_MINER_INSTALLS_MEM_ID)))
+        });
+
+    /// ICP ledger blocks of renewals whose top up was not notified to the
+    /// cycles minting canister yet.
+    static PENDING_TOP_UPS: RefCell<StableBTreeMap<u64, (), VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(PENDING_TOP_UPS_MEM_ID)))
+        });
 }
 
 pub fn insert_block_to_mine(block: Block) {
@@ -108,8 +344,147 @@ pub fn mined_block_count() -> u64 {
     TX_LOG.with(|s| s.borrow().len())
 }
 
+/// Index of the round in progress: every solved block is either pending
+/// or already in the block log.
+pub fn current_round() -> u64 {
+    mined_block_count() + BLOCKS_TO_MINE.with(|s| s.borrow().len())
+}
+
+/// Block index recorded for the miners paid with cycles.
+pub const NO_BLOCK_INDEX: u64 = u64::MAX;
+
+/// Registers a miner, its owner and the ICP block that paid for it.
 pub fn insert_new_miner(miner: Principal, owner: Principal, block_index: u64) {
     MINER_TO_OWNER.with(|s| s.borrow_mut().insert(miner, (owner, block_index)));
+    insert_miner_of(owner, miner);
+    if block_index != NO_BLOCK_INDEX {
+        insert_block_index(block_index);
+    }
+}
+
+fn insert_miner_of(owner: Principal, miner: Principal) {
+    PRINCIPAL_TO_MINER.with(|s| {
+        let mut miners = s
+            .borrow()
+            .get(&owner)
+            .map(|miners| miners.0)
+            .unwrap_or_default();
+        miners.push(miner);
+        s.borrow_mut().insert(owner, Cbor(miners));
+    });
+}
+
+pub fn is_miner(miner: Principal) -> bool {
+    MINER_TO_OWNER.with(|s| s.borrow().contains_key(&miner))
+}
+
+pub fn get_miners_of(owner: Principal) -> Vec<Principal> {
+    PRINCIPAL_TO_MINER.with(|s| {
+        s.borrow()
+            .get(&owner)
+            .map(|miners| miners.0)
+            .unwrap_or_default()
+    })
+}
+
+pub fn get_principal_to_miner() -> Vec<(Principal, Vec<Principal>)> {
+    PRINCIPAL_TO_MINER.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(owner, miners)| (owner, miners.0))
+            .collect()
+    })
+}
+
+pub fn get_mined_block_count_of(miner: Principal) -> u64 {
+    MINER_TO_MINED_BLOCK.with(|s| s.borrow().get(&miner).unwrap_or(0))
+}
+
+fn increment_mined_block_of(miner: Principal) {
+    MINER_TO_MINED_BLOCK.with(|s| {
+        let count = s.borrow().get(&miner).unwrap_or(0);
+        s.borrow_mut().insert(miner, count + 1);
+    });
+}
+
+/// Credits a solved block to a miner.
+pub fn record_mined_block(miner: Principal, timestamp: u64) {
+    increment_mined_block_of(miner);
+    update_counters(|c| {
+        c.blocks_mined += 1;
+        c.last_solved_challenge_ts = timestamp;
+    });
+}
+
+fn get_counters() -> Counters {
+    COUNTERS.with(|s| s.borrow().get().0.clone())
+}
+
+fn update_counters(f: impl FnOnce(&mut Counters)) {
+    let mut counters = get_counters();
+    f(&mut counters);
+    COUNTERS
+        .with(|s| s.borrow_mut().set(Cbor(counters)))
+        .expect("failed to set the counters");
+}
+
+/// Number of blocks solved by the miners spawned by this minter.
+pub fn blocks_mined_count() -> u64 {
+    get_counters().blocks_mined
+}
+
+pub fn last_solved_challenge_ts() -> u64 {
+    get_counters().last_solved_challenge_ts
+}
+
+pub fn init_stable_layout(now: u64) {
+    update_counters(|c| {
+        c.layout_version = STABLE_LAYOUT_VERSION;
+        c.last_solved_challenge_ts = now;
+    });
+}
+
+/// Recomputes the number of blocks solved by each miner from the block log
+/// and the blocks waiting for their rewards.
+pub fn rebuild_mined_block_counters() {
+    MINER_TO_MINED_BLOCK.with(|s| {
+        let miners: Vec<Principal> = s.borrow().iter().map(|(miner, _)| miner).collect();
+        for miner in miners {
+            s.borrow_mut().remove(&miner);
+        }
+    });
+
+    let mut blocks_mined = 0;
+    let solved_blocks = get_mined_block().into_iter().chain(get_block_to_mine());
+    for block in solved_blocks {
+        if let Some(miner) = block.miner {
+            increment_mined_block_of(miner);
+            blocks_mined += 1;
+        }
+    }
+
+    update_counters(|c| c.blocks_mined = blocks_mined);
+}
+
+/// Rebuilds the per-owner and per-miner indices from the miner registry and
+/// the block log. This walks the whole history and therefore only runs once,
+/// on the first upgrade to the stable layout.
+pub fn migrate_to_stable_layout(now: u64) {
+    if get_counters().layout_version >= STABLE_LAYOUT_VERSION {
+        return;
+    }
+
+    for (miner, (owner, block_index)) in get_miner_to_owner_and_index() {
+        insert_miner_of(owner, miner);
+        insert_block_index(block_index);
+    }
+
+    rebuild_mined_block_counters();
+
+    update_counters(|c| {
+        c.layout_version = STABLE_LAYOUT_VERSION;
+        c.last_solved_challenge_ts = now;
+    });
 }
 
 pub fn get_miner_owner(miner: Principal) -> Option<Principal> {
@@ -144,6 +519,18 @@ pub fn get_expire_map() -> Vec<(Principal, u64)> {
     USER_TO_EXPIRATION.with(|s| s.borrow().iter().collect())
 }
 
+/// Returns up to `length` pool members with their expiration, in principal
+/// order, skipping the first `start` ones.
+pub fn get_expire_map_page(start: u64, length: u64) -> Vec<(Principal, u64)> {
+    USER_TO_EXPIRATION.with(|s| {
+        s.borrow()
+            .iter()
+            .skip(start as usize)
+            .take(length as usize)
+            .collect()
+    })
+}
+
 pub fn remove_expired_entries(current_time: u64) {
     USER_TO_EXPIRATION.with(|s| {
         let mut map = s.borrow_mut();
@@ -167,3 +554,546 @@ pub fn is_known_block(block_index: u64) -> bool {
 pub fn insert_block_index(block_index: u64) {
     KNOWN_INDEX.with(|s| s.borrow_mut().insert(block_index, ()));
 }
+
+pub fn get_known_indices() -> Vec<u64> {
+    KNOWN_INDEX.with(|s| s.borrow().iter().map(|(index, _)| index).collect())
+}
+
+pub fn insert_admin(admin: Principal) -> bool {
+    ADMINS.with(|s| s.borrow_mut().insert(admin, ()).is_none())
+}
+
+pub fn remove_admin(admin: Principal) -> bool {
+    ADMINS.with(|s| s.borrow_mut().remove(&admin).is_some())
+}
+
+pub fn is_admin(principal: Principal) -> bool {
+    ADMINS.with(|s| s.borrow().contains_key(&principal))
+}
+
+pub fn get_admins() -> Vec<Principal> {
+    ADMINS.with(|s| s.borrow().iter().map(|(k, _)| k).collect())
+}
+
+pub fn pause_subsystem(subsystem: Subsystem) -> bool {
+    PAUSED_SUBSYSTEMS.with(|s| s.borrow_mut().insert(Cbor(subsystem), ()).is_none())
+}
+
+pub fn resume_subsystem(subsystem: Subsystem) -> bool {
+    PAUSED_SUBSYSTEMS.with(|s| s.borrow_mut().remove(&Cbor(subsystem)).is_some())
+}
+
+pub fn is_paused(subsystem: Subsystem) -> bool {
+    PAUSED_SUBSYSTEMS.with(|s| s.borrow().contains_key(&Cbor(subsystem)))
+}
+
+pub fn get_paused_subsystems() -> Vec<Subsystem> {
+    PAUSED_SUBSYSTEMS.with(|s| s.borrow().iter().map(|(k, _)| k.0).collect())
+}
+
+pub fn push_audit_entry(entry: AuditEntry) {
+    AUDIT_LOG
+        .with(|s| s.borrow().append(&Cbor(entry)))
+        .expect("failed to push audit entry");
+}
+
+pub fn get_audit_entry(index: u64) -> Option<AuditEntry> {
+    AUDIT_LOG.with(|s| s.borrow().get(index).map(|e| e.0))
+}
+
+pub fn audit_log_len() -> u64 {
+    AUDIT_LOG.with(|s| s.borrow().len())
+}
+
+pub fn init_miner_stats(miner: Principal) {
+    MINER_STATS.with(|s| {
+        s.borrow_mut()
+            .insert(miner, Cbor(MinerStats::new(current_round())))
+    });
+}
+
+pub fn record_miner_submission(miner: Principal, cycles: u64, now: u64) {
+    let round = current_round();
+    MINER_STATS.with(|s| {
+        let mut stats = s
+            .borrow()
+            .get(&miner)
+            .map(|stats| stats.0)
+            .unwrap_or_else(|| MinerStats::new(round));
+        stats.last_submission_ts = Some(now);
+        stats.last_submission_round = round;
+        stats.total_cycles_submitted = stats.total_cycles_submitted.saturating_add(cycles);
+        s.borrow_mut().insert(miner, Cbor(stats));
+    });
+}
+
+pub fn get_miner_stats(miner: Principal) -> Option<MinerStats> {
+    MINER_STATS.with(|s| s.borrow().get(&miner).map(|stats| stats.0))
+}
+
+pub fn record_payout(ledger_index: u64, payout: Payout) {
+    PAYOUTS.with(|s| s.borrow_mut().insert(ledger_index, Cbor(payout)));
+}
+
+pub fn get_payout(ledger_index: u64) -> Option<Payout> {
+    PAYOUTS.with(|s| s.borrow().get(&ledger_index).map(|p| p.0))
+}
+
+pub fn min_payout_ledger_index() -> Option<u64> {
+    PAYOUTS.with(|s| s.borrow().iter().next().map(|(k, _)| k))
+}
+
+pub fn get_reconciliation_state() -> ReconciliationState {
+    RECONCILIATION_STATE.with(|s| s.borrow().get().0.clone())
+}
+
+pub fn set_reconciliation_state(state: ReconciliationState) {
+    RECONCILIATION_STATE
+        .with(|s| s.borrow_mut().set(Cbor(state)))
+        .expect("failed to set the reconciliation state");
+}
+
+/// Records the mint of a block to an owner, returning the ledger index of
+/// a previous mint of the same block to the same owner if there was one.
+pub fn record_seen_mint(block_timestamp: u64, to: Principal, ledger_index: u64) -> Option<u64> {
+    SEEN_MINTS.with(|s| {
+        let key = Cbor((block_timestamp, to));
+        if let Some(first_ledger_index) = s.borrow().get(&key) {
+            return Some(first_ledger_index);
+        }
+        s.borrow_mut().insert(key, ledger_index);
+        None
+    })
+}
+
+pub fn push_discrepancy(record: DiscrepancyRecord) {
+    DISCREPANCIES
+        .with(|s| s.borrow().append(&Cbor(record)))
+        .expect("failed to push discrepancy");
+}
+
+pub fn get_discrepancy(index: u64) -> Option<DiscrepancyRecord> {
+    DISCREPANCIES.with(|s| s.borrow().get(index).map(|d| d.0))
+}
+
+pub fn discrepancy_count() -> u64 {
+    DISCREPANCIES.with(|s| s.borrow().len())
+}
+
+pub fn set_auto_renewal(owner: Principal, renewal: AutoRenewal) {
+    AUTO_RENEWALS.with(|s| s.borrow_mut().insert(owner, Cbor(renewal)));
+}
+
+pub fn remove_auto_renewal(owner: Principal) -> Option<AutoRenewal> {
+    AUTO_RENEWALS.with(|s| s.borrow_mut().remove(&owner).map(|r| r.0))
+}
+
+pub fn get_auto_renewal(owner: Principal) -> Option<AutoRenewal> {
+    AUTO_RENEWALS.with(|s| s.borrow().get(&owner).map(|r| r.0))
+}
+
+pub fn get_auto_renewals() -> Vec<(Principal, AutoRenewal)> {
+    AUTO_RENEWALS.with(|s| s.borrow().iter().map(|(k, v)| (k, v.0)).collect())
+}
+
+pub fn insert_pending_top_up(block_index: u64) {
+    PENDING_TOP_UPS.with(|s| s.borrow_mut().insert(block_index, ()));
+}
+
+pub fn remove_pending_top_up(block_index: u64) {
+    PENDING_TOP_UPS.with(|s| s.borrow_mut().remove(&block_index));
+}
+
+pub fn get_pending_top_ups() -> Vec<u64> {
+    PENDING_TOP_UPS.with(|s| s.borrow().iter().map(|(k, _)| k).collect())
+}
+
+pub fn push_renewal_record(record: RenewalRecord) {
+    RENEWAL_LOG
+        .with(|s| s.borrow().append(&Cbor(record)))
+        .expect("failed to push renewal record");
+}
+
+pub fn get_renewal_record(index: u64) -> Option<RenewalRecord> {
+    RENEWAL_LOG.with(|s| s.borrow().get(index).map(|r| r.0))
+}
+
+pub fn renewal_log_len() -> u64 {
+    RENEWAL_LOG.with(|s| s.borrow().len())
+}
+
+fn update_pool_member_stats(owner: Principal, f: impl FnOnce(&mut PoolMemberStats)) {
+    POOL_MEMBER_STATS.with(|s| {
+        let mut stats = s
+            .borrow()
+            .get(&owner)
+            .map(|stats| stats.0)
+            .unwrap_or_default();
+        f(&mut stats);
+        s.borrow_mut().insert(owner, Cbor(stats));
+    });
+}
+
+pub fn record_pool_payment(owner: Principal, amount_e8s: u64) {
+    update_pool_member_stats(owner, |stats| {
+        stats.amount_paid_e8s = stats.amount_paid_e8s.saturating_add(amount_e8s)
+    });
+}
+
+pub fn record_pool_cycles_payment(owner: Principal, cycles: u64) {
+    update_pool_member_stats(owner, |stats| {
+        stats.cycles_paid = stats.cycles_paid.saturating_add(cycles)
+    });
+}
+
+pub fn record_pool_reward(owner: Principal, rewards: u64) {
+    update_pool_member_stats(owner, |stats| {
+        stats.rewards_earned = stats.rewards_earned.saturating_add(rewards)
+    });
+}
+
+pub fn get_pool_member_stats(owner: Principal) -> PoolMemberStats {
+    POOL_MEMBER_STATS.with(|s| {
+        s.borrow()
+            .get(&owner)
+            .map(|stats| stats.0)
+            .unwrap_or_default()
+    })
+}
+
+pub fn push_event(event: Event) {
+    EVENT_LOG
+        .with(|s| s.borrow().append(&Cbor(event)))
+        .expect("failed to push event");
+}
+
+pub fn get_event(index: u64) -> Option<Event> {
+    EVENT_LOG.with(|s| s.borrow().get(index).map(|e| e.0))
+}
+
+pub fn event_count() -> u64 {
+    EVENT_LOG.with(|s| s.borrow().len())
+}
+
+pub fn credit_claimable(owner: Principal, amount: u64) {
+    CLAIMABLE.with(|s| {
+        let balance = s.borrow().get(&owner).unwrap_or(0);
+        s.borrow_mut().insert(owner, balance.saturating_add(amount));
+    });
+}
+
+/// Removes and returns the claimable balance of `owner`.
+pub fn take_claimable(owner: Principal) -> u64 {
+    CLAIMABLE.with(|s| s.borrow_mut().remove(&owner).unwrap_or(0))
+}
+
+pub fn get_claimable(owner: Principal) -> u64 {
+    CLAIMABLE.with(|s| s.borrow().get(&owner).unwrap_or(0))
+}
+
+pub fn total_claimable() -> u64 {
+    CLAIMABLE.with(|s| s.borrow().iter().map(|(_, v)| v).sum())
+}
+
+pub fn get_claimables() -> Vec<(Principal, u64)> {
+    CLAIMABLE.with(|s| s.borrow().iter().collect())
+}
+
+pub fn set_claim_mode(owner: Principal, enabled: bool) {
+    CLAIM_MODE.with(|s| {
+        if enabled {
+            s.borrow_mut().insert(owner, ());
+        } else {
+            s.borrow_mut().remove(&owner);
+        }
+    });
+}
+
+pub fn is_claim_mode(owner: Principal) -> bool {
+    CLAIM_MODE.with(|s| s.borrow().contains_key(&owner))
+}
+
+pub fn get_pool_payout_job() -> Option<PoolPayoutJob> {
+    POOL_PAYOUT_JOB.with(|s| s.borrow().get().0.clone())
+}
+
+pub fn set_pool_payout_job(job: Option<PoolPayoutJob>) {
+    POOL_PAYOUT_JOB
+        .with(|s| s.borrow_mut().set(Cbor(job)))
+        .expect("failed to set the pool payout job");
+}
+
+pub fn enqueue_pool_payout(owner: Principal) {
+    POOL_PAYOUT_QUEUE.with(|s| s.borrow_mut().insert(owner, ()));
+}
+
+/// Returns up to `count` members waiting for their payout, they stay queued
+/// until `remove_pool_payout`.
+pub fn get_pool_payouts(count: u64) -> Vec<Principal> {
+    POOL_PAYOUT_QUEUE.with(|s| {
+        s.borrow()
+            .iter()
+            .take(count as usize)
+            .map(|(k, _)| k)
+            .collect()
+    })
+}
+
+pub fn remove_pool_payout(owner: Principal) {
+    POOL_PAYOUT_QUEUE.with(|s| s.borrow_mut().remove(&owner));
+}
+
+pub fn pool_payout_queue_len() -> u64 {
+    POOL_PAYOUT_QUEUE.with(|s| s.borrow().len())
+}
+
+pub fn get_pool_payout_concurrency() -> u64 {
+    POOL_PAYOUT_CONCURRENCY.with(|s| *s.borrow().get())
+}
+
+pub fn set_pool_payout_concurrency(concurrency: u64) {
+    POOL_PAYOUT_CONCURRENCY
+        .with(|s| s.borrow_mut().set(concurrency))
+        .expect("failed to set the pool payout concurrency");
+}
+
+pub fn insert_pending_payment(block_index: u64, payment: PendingPayment) {
+    PENDING_PAYMENTS.with(|s| s.borrow_mut().insert(block_index, Cbor(payment)));
+}
+
+pub fn remove_pending_payment(block_index: u64) -> Option<PendingPayment> {
+    PENDING_PAYMENTS.with(|s| s.borrow_mut().remove(&block_index).map(|p| p.0))
+}
+
+pub fn get_pending_payment(block_index: u64) -> Option<PendingPayment> {
+    PENDING_PAYMENTS.with(|s| s.borrow().get(&block_index).map(|p| p.0))
+}
+
+pub fn get_pending_payments() -> Vec<(u64, PendingPayment)> {
+    PENDING_PAYMENTS.with(|s| s.borrow().iter().map(|(k, v)| (k, v.0)).collect())
+}
+
+pub fn insert_deny_list_entry(entry: DenyListEntry) -> bool {
+    DENY_LIST.with(|s| {
+        s.borrow_mut()
+            .insert(entry.principal, Cbor(entry))
+            .is_none()
+    })
+}
+
+pub fn remove_deny_list_entry(principal: Principal) -> bool {
+    DENY_LIST.with(|s| s.borrow_mut().remove(&principal).is_some())
+}
+
+pub fn is_denied(principal: Principal) -> bool {
+    DENY_LIST.with(|s| s.borrow().contains_key(&principal))
+}
+
+pub fn get_deny_list() -> Vec<DenyListEntry> {
+    DENY_LIST.with(|s| s.borrow().iter().map(|(_, v)| v.0).collect())
+}
+
+/// Records a registered miner whose code still has to be installed.
+pub fn insert_pending_miner_install(miner: Principal, owner: Principal) {
+    PENDING_MINER_INSTALLS.with(|s| s.borrow_mut().insert(miner, owner));
+}
+
+pub fn remove_pending_miner_install(miner: Principal) {
+    PENDING_MINER_INSTALLS.with(|s| s.borrow_mut().remove(&miner));
+}
+
+pub fn get_pending_miner_installs() -> Vec<(Principal, Principal)> {
+    PENDING_MINER_INSTALLS.with(|s| s.borrow().iter().collect())
+}
+
+/// Registers a reward stream and returns its id.
+pub fn insert_reward_stream(stream: RewardStream) -> u64 {
+    REWARD_STREAMS.with(|s| {
+        let id = s.borrow().last_key_value().map(|(k, _)| k + 1).unwrap_or(0);
+        s.borrow_mut().insert(id, Cbor(stream));
+        id
+    })
+}
+
+pub fn update_reward_stream(id: u64, stream: RewardStream) {
+    REWARD_STREAMS.with(|s| s.borrow_mut().insert(id, Cbor(stream)));
+}
+
+pub fn get_reward_stream(id: u64) -> Option<RewardStream> {
+    REWARD_STREAMS.with(|s| s.borrow().get(&id).map(|v| v.0))
+}
+
+pub fn get_reward_streams() -> Vec<(u64, RewardStream)> {
+    REWARD_STREAMS.with(|s| s.borrow().iter().map(|(k, v)| (k, v.0)).collect())
+}
+
+pub fn enqueue_extra_payout(
+    block_timestamp: u64,
+    to: Principal,
+    ledger_id: Principal,
+    amount: u64,
+) {
+    EXTRA_PAYOUTS.with(|s| {
+        let key = Cbor((block_timestamp, to, ledger_id));
+        let payout = match s.borrow().get(&key) {
+            Some(Cbor(mut payout)) => {
+                payout.amount = payout.amount.saturating_add(amount);
+                payout
+            }
+            None => ExtraPayout::new(amount),
+        };
+        s.borrow_mut().insert(key, Cbor(payout));
+    });
+}
+
+pub fn insert_extra_payout(
+    block_timestamp: u64,
+    to: Principal,
+    ledger_id: Principal,
+    payout: ExtraPayout,
+) {
+    EXTRA_PAYOUTS.with(|s| {
+        s.borrow_mut()
+            .insert(Cbor((block_timestamp, to, ledger_id)), Cbor(payout))
+    });
+}
+
+pub fn remove_extra_payout(block_timestamp: u64, to: Principal, ledger_id: Principal) {
+    EXTRA_PAYOUTS.with(|s| {
+        s.borrow_mut()
+            .remove(&Cbor((block_timestamp, to, ledger_id)))
+    });
+}
+
+/// Returns the secondary rewards waiting to be paid.
+pub fn get_extra_payouts() -> Vec<((u64, Principal, Principal), ExtraPayout)> {
+    EXTRA_PAYOUTS.with(|s| s.borrow().iter().map(|(k, v)| (k.0, v.0)).collect())
+}
+
+pub fn extra_payout_count() -> u64 {
+    EXTRA_PAYOUTS.with(|s| s.borrow().len())
+}
+
+pub fn insert_bob_lock(owner: Principal, lock: BobLock) {
+    BOB_LOCKS.with(|s| s.borrow_mut().insert(owner, Cbor(lock)));
+}
+
+pub fn remove_bob_lock(owner: Principal) -> Option<BobLock> {
+    BOB_LOCKS.with(|s| s.borrow_mut().remove(&owner).map(|l| l.0))
+}
+
+pub fn get_bob_lock(owner: Principal) -> Option<BobLock> {
+    BOB_LOCKS.with(|s| s.borrow().get(&owner).map(|l| l.0))
+}
+
+pub fn get_bob_locks() -> Vec<(Principal, BobLock)> {
+    BOB_LOCKS.with(|s| s.borrow().iter().map(|(k, v)| (k, v.0)).collect())
+}
+
+pub fn get_vesting_params() -> VestingParams {
+    VESTING_PARAMS.with(|s| s.borrow().get().0.clone())
+}
+
+pub fn set_vesting_params(params: VestingParams) {
+    VESTING_PARAMS
+        .with(|s| s.borrow_mut().set(Cbor(params)))
+        .expect("failed to set the vesting parameters");
+}
+
+pub fn insert_vesting_schedule(owner: Principal, schedule: VestingSchedule) {
+    VESTING_SCHEDULES.with(|s| {
+        s.borrow_mut()
+            .insert(Cbor((owner, schedule.block_timestamp)), Cbor(schedule))
+    });
+}
+
+pub fn remove_vesting_schedule(owner: Principal, block_timestamp: u64) {
+    VESTING_SCHEDULES.with(|s| s.borrow_mut().remove(&Cbor((owner, block_timestamp))));
+}
+
+pub fn get_vesting_schedule(owner: Principal, block_timestamp: u64) -> Option<VestingSchedule> {
+    VESTING_SCHEDULES.with(|s| s.borrow().get(&Cbor((owner, block_timestamp))).map(|v| v.0))
+}
+
+pub fn get_vesting_schedules_of(owner: Principal) -> Vec<VestingSchedule> {
+    VESTING_SCHEDULES.with(|s| {
+        s.borrow()
+            .range(Cbor((owner, 0))..=Cbor((owner, u64::MAX)))
+            .map(|(_, v)| v.0)
+            .collect()
+    })
+}
+
+pub fn get_vesting_schedules() -> Vec<(Principal, VestingSchedule)> {
+    VESTING_SCHEDULES.with(|s| s.borrow().iter().map(|(k, v)| (k.0 .0, v.0)).collect())
+}
+
+/// Owners with at least one vesting schedule.
+pub fn vesting_owners() -> Vec<Principal> {
+    VESTING_SCHEDULES.with(|s| {
+        let mut owners: Vec<Principal> = s.borrow().iter().map(|(k, _)| k.0 .0).collect();
+        owners.dedup();
+        owners
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINER: Principal = Principal::from_slice(&[0x01]);
+    const OWNER: Principal = Principal::from_slice(&[0x02]);
+
+    fn mined_block(miner: Principal, timestamp: u64) -> Block {
+        Block {
+            to: OWNER,
+            miner: Some(miner),
+            miner_cycles_burned: Some(1_000_000_000),
+            total_cycles_burned: Some(1_000_000_000),
+            timestamp,
+            rewards: 100,
+            miner_count: Some(1),
+            extra_rewards: None,
+        }
+    }
+
+    #[test]
+    fn should_migrate_to_the_stable_layout_once() {
+        // The legacy layout only has the miner registry and the block log.
+        MINER_TO_OWNER.with(|s| s.borrow_mut().insert(MINER, (OWNER, 7)));
+        push_block(mined_block(MINER, 1));
+        push_block(mined_block(MINER, 2));
+
+        migrate_to_stable_layout(10);
+
+        assert_eq!(get_miners_of(OWNER), vec![MINER]);
+        assert!(is_known_block(7));
+        assert_eq!(get_mined_block_count_of(MINER), 2);
+        assert_eq!(blocks_mined_count(), 2);
+        assert_eq!(last_solved_challenge_ts(), 10);
+
+        migrate_to_stable_layout(20);
+
+        assert_eq!(get_miners_of(OWNER), vec![MINER]);
+        assert_eq!(get_mined_block_count_of(MINER), 2);
+        assert_eq!(blocks_mined_count(), 2);
+        assert_eq!(last_solved_challenge_ts(), 10);
+        assert_eq!(mined_block_count(), 2);
+    }
+
+    #[test]
+    fn should_page_through_the_expire_map() {
+        let members: Vec<(Principal, u64)> = (0..5_u8)
+            .map(|i| (Principal::from_slice(&[0x10, i]), i as u64))
+            .collect();
+        for (owner, expiration) in members.iter() {
+            insert_expiration(*owner, *expiration);
+        }
+
+        assert_eq!(get_expire_map_page(0, 2), members[..2].to_vec());
+        assert_eq!(get_expire_map_page(4, 2), members[4..].to_vec());
+        assert_eq!(get_expire_map_page(5, 2), vec![]);
+        assert_eq!(get_expire_map_page(u64::MAX, 2), vec![]);
+        assert_eq!(get_expire_map_page(0, 0), vec![]);
+    }
+}
@@ -0,0 +1,138 @@
+use crate::memory::{
+    get_block_to_mine, get_reconciliation_state, last_solved_challenge_ts, miner_count,
+    total_claimable, user_count,
+};
+use crate::read_state;
+use ic_metrics_encoder::MetricsEncoder;
+
+const WASM_PAGE_SIZE_IN_BYTES: u64 = 64 * 1024;
+
+pub fn encode_metrics(metrics: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
+    metrics.encode_gauge(
+        "bob_minter_cycle_balance",
+        ic_cdk::api::canister_balance128() as f64,
+        "Cycle balance of the minter.",
+    )?;
+
+    metrics.encode_gauge(
+        "bob_minter_stable_memory_bytes",
+        (ic_cdk::api::stable::stable_size() * WASM_PAGE_SIZE_IN_BYTES) as f64,
+        "Size of the stable memory allocated by the minter.",
+    )?;
+
+    metrics.encode_gauge(
+        "bob_minter_task_queue_length",
+        crate::tasks::get_task_queue().len() as f64,
+        "Number of tasks waiting in the minter task queue.",
+    )?;
+
+    metrics.encode_gauge(
+        "bob_minter_pending_blocks",
+        get_block_to_mine().len() as f64,
+        "Number of solved blocks waiting for their rewards to be paid.",
+    )?;
+
+    metrics.encode_gauge(
+        "bob_minter_registered_miners",
+        miner_count() as f64,
+        "Number of miners spawned by the minter, including the pool.",
+    )?;
+
+    metrics.encode_gauge(
+        "bob_minter_pool_users",
+        user_count() as f64,
+        "Number of users with an active pool membership.",
+    )?;
+
+    metrics.encode_gauge(
+        "bob_minter_claimable_rewards",
+        total_claimable() as f64,
+        "Rewards credited to claimable balances and not yet claimed.",
+    )?;
+
+    let reconciliation = get_reconciliation_state();
+
+    metrics.encode_gauge(
+        "bob_minter_reconciliation_last_run_timestamp_seconds",
+        (reconciliation.last_run_ts / crate::SEC_NANOS) as f64,
+        "Timestamp of the last reconciliation with the BOB ledger.",
+    )?;
+
+    metrics.encode_gauge(
+        "bob_minter_reconciliation_lag_blocks",
+        reconciliation
+            .ledger_length
+            .saturating_sub(reconciliation.next_ledger_block.unwrap_or_default()) as f64,
+        "Number of BOB ledger blocks not yet reconciled.",
+    )?;
+
+    metrics.encode_counter(
+        "bob_minter_reconciliation_matched_payouts",
+        reconciliation.matched_payouts as f64,
+        "Number of recorded payouts found in the BOB ledger.",
+    )?;
+
+    metrics.encode_counter(
+        "bob_minter_reconciliation_missing_payouts",
+        reconciliation.missing_payouts as f64,
+        "Number of recorded payouts without a matching mint.",
+    )?;
+
+    metrics.encode_counter(
+        "bob_minter_reconciliation_double_payouts",
+        reconciliation.double_payouts as f64,
+        "Number of blocks minted more than once to the same owner.",
+    )?;
+
+    metrics.encode_counter(
+        "bob_minter_reconciliation_unexpected_mints",
+        reconciliation.unexpected_mints as f64,
+        "Number of mints that do not belong to any block.",
+    )?;
+
+    read_state(|s| {
+        metrics.encode_counter(
+            "bob_minter_blocks_mined",
+            s.total_blocks_mined() as f64,
+            "Total number of blocks mined, including historical blocks.",
+        )?;
+
+        metrics.encode_gauge(
+            "bob_minter_current_rewards",
+            s.current_rewards() as f64,
+            "Reward in BOB e8s for the next block.",
+        )?;
+
+        metrics.encode_gauge(
+            "bob_minter_round_active_miners",
+            s.miner_to_burned_cycles.len() as f64,
+            "Number of miners that submitted burned cycles in the current round.",
+        )?;
+
+        metrics.encode_gauge(
+            "bob_minter_round_burned_cycles",
+            s.miner_to_burned_cycles.values().sum::<u64>() as f64,
+            "Cycles burned by all miners in the current round.",
+        )?;
+
+        metrics.encode_gauge(
+            "bob_minter_last_block_timestamp_seconds",
+            (last_solved_challenge_ts() / crate::SEC_NANOS) as f64,
+            "Timestamp of the last solved block.",
+        )?;
+
+        metrics.encode_gauge(
+            "bob_minter_time_since_last_block_seconds",
+            s.time_since_last_block() as f64,
+            "Seconds elapsed since the last solved block.",
+        )?;
+
+        metrics.encode_counter(
+            "bob_minter_payout_failures",
+            s.payout_failures as f64,
+            "Number of failed reward transfers since the last upgrade.",
+        )?;
+
+        Ok(())
+    })
+}
@@ -1,6 +1,7 @@
-use candid::{CandidType, Principal};
+use candid::{CandidType, Deserialize, Nat, Principal};
 use ic_base_types::PrincipalId;
 use ic_cdk::api::call::RejectionCode;
+use ic_cdk::api::management_canister::main::CanisterStatusType;
 use ic_management_canister_types::{
     CanisterIdRecord, CanisterInstallMode, CanisterSettingsArgsBuilder, CreateCanisterArgs,
     InstallCodeArgs,
@@ -145,3 +146,34 @@ pub async fn create_canister(cycles_for_canister_creation: u64) -> Result<Princi
 
     Ok(result.get_canister_id().get().into())
 }
+
+/// Status of a miner as reported by the management canister.
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct MinerStatus {
+    pub status: CanisterStatusType,
+    pub cycles: Nat,
+    pub memory_size: Nat,
+    pub freezing_threshold: Nat,
+    pub module_hash: Option<Vec<u8>>,
+    pub idle_cycles_burned_per_day: Nat,
+}
+
+pub async fn canister_status(canister_id: Principal) -> Result<MinerStatus, CallError> {
+    let (response,) = ic_cdk::api::management_canister::main::canister_status(
+        ic_cdk::api::management_canister::main::CanisterIdRecord { canister_id },
+    )
+    .await
+    .map_err(|(code, msg)| CallError {
+        method: "canister_status".to_string(),
+        reason: Reason::from_reject(code, msg),
+    })?;
+
+    Ok(MinerStatus {
+        status: response.status,
+        cycles: response.cycles,
+        memory_size: response.memory_size,
+        freezing_threshold: response.settings.freezing_threshold,
+        module_hash: response.module_hash,
+        idle_cycles_burned_per_day: response.idle_cycles_burned_per_day,
+    })
+}
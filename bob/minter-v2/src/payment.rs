@@ -0,0 +1,704 @@
+//! Verification of the ICP payments made to spawn miners and join the pool.
+//!
+//! Blocks are looked up in the ICP index first. The index can lag behind the
+//! ledger, so missing blocks are then fetched from the ICP ledger and its
+//! archives. Payments whose block is not available yet can be queued, the
+//! minter completes them once the block appears.
+
+use crate::admin::Subsystem;
+use crate::events::{record_event, EventType};
+use crate::logs::{DEBUG, INFO};
+use crate::memory::{
+    get_expiration, get_pending_miner_installs, get_pending_payment, get_pending_payments,
+    init_miner_stats, insert_block_index, insert_expiration, insert_new_miner,
+    insert_pending_miner_install, insert_pending_payment, is_known_block, is_paused,
+    record_pool_cycles_payment, record_pool_payment, remove_pending_miner_install,
+    remove_pending_payment, NO_BLOCK_INDEX,
+};
+use crate::runtime::Runtime;
+use crate::tasks::{schedule_after, TaskType};
+use crate::{
+    miner_wasm, CYCLES_PER_USER_PER_ROUND, DAY_NANOS, E8S_PER_POOL_DAY, MAINNET_LEDGER_CANISTER_ID,
+    MEMO_TOP_UP_CANISTER, SEC_NANOS,
+};
+use candid::{CandidType, Encode, Nat, Principal};
+use ic_canister_log::log;
+use ic_ledger_core::block::BlockType;
+use icp_ledger::{AccountIdentifier, Operation};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+
+/// Payments are rounded down to whole ICP, this leaves room for the fee.
+const MIN_PAYMENT_E8S: u64 = 99_990_000;
+pub const CYCLES_FOR_CREATION: u64 = 2_500_000_000_000;
+/// Price of a day in the pool paid with cycles: enough to burn for the
+/// member in every round of the day, rounds last at least 400 seconds.
+pub const CYCLES_PER_POOL_DAY: u64 = CYCLES_PER_USER_PER_ROUND * (DAY_NANOS / SEC_NANOS / 400);
+const MAX_POOL_DAYS_PER_PAYMENT: u64 = 365;
+/// Queued payments are dropped when their block does not show up in time.
+const PENDING_PAYMENT_TIMEOUT_NANOS: u64 = 60 * 60 * SEC_NANOS;
+/// Delay between two attempts to install the code of a spawned miner.
+pub const INSTALL_RETRY_INTERVAL_SECS: u64 = 60;
+/// Maximum number of queued payments accepted per owner.
+const MAX_PENDING_PAYMENTS_PER_OWNER: usize = 5;
+
+const ICP_INDEX_CANISTER_ID: &str = "qhbym-qaaaa-aaaaa-aaafq-cai";
+/// Top up account of the minter at the cycles minting canister.
+const MINTER_ACCOUNT: &str = "e7b583c3e3e2837c987831a97a6b980cbb0be89819e85915beb3c02006923fce";
+/// Top up account of the first version of the minter, still accepted for
+/// spawning miners.
+const OLD_MINTER_ACCOUNT: &str = "6b896884e0b42634eca9c68c435c47b0ef2b97cf874a17198856b9c4efe89249";
+
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, CandidType,
+)]
+pub enum PaymentKind {
+    SpawnMiner,
+    JoinPool,
+}
+
+impl PaymentKind {
+    /// Subsystem whose pause switch applies to the payment.
+    pub fn subsystem(&self) -> Subsystem {
+        match self {
+            PaymentKind::SpawnMiner => Subsystem::Spawning,
+            PaymentKind::JoinPool => Subsystem::PoolJoins,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub enum PaymentError {
+    /// The block is not in the ledger yet, the payment can be retried.
+    NotYetAvailable { chain_length: u64 },
+    /// A call to the ledger, the index or the cycles minting canister
+    /// failed, the payment can be retried.
+    CallFailed(String),
+    /// The block does not pay for the request.
+    Invalid(String),
+}
+
+impl PaymentError {
+    pub fn is_retryable(&self) -> bool {
+        !matches!(self, PaymentError::Invalid(_))
+    }
+}
+
+impl fmt::Display for PaymentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaymentError::NotYetAvailable { chain_length } => write!(
+                f,
+                "block not yet indexed (the ledger has {chain_length} blocks), retry later or call queue_payment"
+            ),
+            PaymentError::CallFailed(e) => write!(f, "{e}"),
+            PaymentError::Invalid(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub struct PendingPayment {
+    pub owner: Principal,
+    pub kind: PaymentKind,
+    pub queued_at: u64,
+    pub attempts: u64,
+    pub last_error: Option<String>,
+}
+
+async fn fetch_from_index(block_index: u64) -> Result<Option<icp_ledger::Block>, PaymentError> {
+    let request = icrc_ledger_types::icrc3::blocks::GetBlocksRequest {
+        start: block_index.into(),
+        length: Nat::from(1_u8),
+    };
+    let (response,): (ic_icp_index::GetBlocksResponse,) = ic_cdk::call(
+        Principal::from_text(ICP_INDEX_CANISTER_ID).unwrap(),
+        "get_blocks",
+        (request,),
+    )
+    .await
+    .map_err(|(code, msg)| {
+        PaymentError::CallFailed(format!("failed to call the ICP index ({code:?}): {msg}"))
+    })?;
+    response
+        .blocks
+        .first()
+        .map(|block| icp_ledger::Block::decode(block.clone()).map_err(PaymentError::Invalid))
+        .transpose()
+}
+
+async fn fetch_from_ledger(block_index: u64) -> Result<icp_ledger::Block, PaymentError> {
+    let args = icp_ledger::GetBlocksArgs {
+        start: block_index,
+        length: 1,
+    };
+    let (response,): (icp_ledger::QueryBlocksResponse,) =
+        ic_cdk::call(MAINNET_LEDGER_CANISTER_ID, "query_blocks", (args.clone(),))
+            .await
+            .map_err(|(code, msg)| {
+                PaymentError::CallFailed(format!("failed to call the ICP ledger ({code:?}): {msg}"))
+            })?;
+
+    if block_index >= response.chain_length {
+        return Err(PaymentError::NotYetAvailable {
+            chain_length: response.chain_length,
+        });
+    }
+
+    let candid_block = if let Some(block) = response.blocks.into_iter().next() {
+        block
+    } else {
+        let archive = response
+            .archived_blocks
+            .into_iter()
+            .find(|range| range.start <= block_index && block_index < range.start + range.length)
+            .ok_or(PaymentError::CallFailed(format!(
+                "block {block_index} is neither in the ledger nor in an archive"
+            )))?;
+        let (result,): (icp_ledger::GetBlocksResult,) = ic_cdk::call(
+            archive.callback.canister_id,
+            &archive.callback.method,
+            (args,),
+        )
+        .await
+        .map_err(|(code, msg)| {
+            PaymentError::CallFailed(format!("failed to call the ICP archive ({code:?}): {msg}"))
+        })?;
+        result
+            .map_err(|e| PaymentError::CallFailed(format!("{e:?}")))?
+            .blocks
+            .into_iter()
+            .next()
+            .ok_or(PaymentError::CallFailed(format!(
+                "block {block_index} is missing from its archive"
+            )))?
+    };
+    icp_ledger::Block::try_from(candid_block).map_err(PaymentError::Invalid)
+}
+
+/// Fetches an ICP ledger block from the index, falling back to the ledger
+/// and its archives if the index did not sync it yet.
+pub async fn fetch_block(block_index: u64) -> Result<icp_ledger::Block, PaymentError> {
+    match fetch_from_index(block_index).await {
+        Ok(Some(block)) => return Ok(block),
+        Ok(None) => log!(
+            DEBUG,
+            "[fetch_block] block {block_index} is not indexed yet, querying the ledger"
+        ),
+        Err(e) => log!(
+            DEBUG,
+            "[fetch_block] failed to fetch block {block_index} from the index: {e}, querying the ledger"
+        ),
+    }
+    fetch_from_ledger(block_index).await
+}
+
+/// Checks that the block pays for the request of `owner` and returns the
+/// amount paid in e8s.
+pub async fn verify_payment<R: Runtime>(
+    runtime: &R,
+    owner: Principal,
+    block_index: u64,
+    kind: PaymentKind,
+) -> Result<u64, PaymentError> {
+    if is_known_block(block_index) {
+        return Err(PaymentError::Invalid(
+            "already consumed block index".to_string(),
+        ));
+    }
+
+    let transaction = runtime.fetch_icp_block(block_index).await?.transaction;
+
+    if transaction.memo != icp_ledger::Memo(MEMO_TOP_UP_CANISTER) {
+        return Err(PaymentError::Invalid("unknown memo".to_string()));
+    }
+
+    let Operation::Transfer {
+        from, to, amount, ..
+    } = transaction.operation
+    else {
+        return Err(PaymentError::Invalid("expected transfer".to_string()));
+    };
+
+    if from != AccountIdentifier::new(ic_types::PrincipalId(owner), None) {
+        return Err(PaymentError::Invalid("unexpected caller".to_string()));
+    }
+    let expected_to = AccountIdentifier::from_hex(MINTER_ACCOUNT).unwrap();
+    let old_to = AccountIdentifier::from_hex(OLD_MINTER_ACCOUNT).unwrap();
+    if to != expected_to && !(kind == PaymentKind::SpawnMiner && to == old_to) {
+        return Err(PaymentError::Invalid("unexpected destination".to_string()));
+    }
+    if amount.get_e8s() < MIN_PAYMENT_E8S {
+        return Err(PaymentError::Invalid("amount too low".to_string()));
+    }
+
+    Ok(amount.get_e8s())
+}
+
+async fn top_up<R: Runtime>(runtime: &R, block_index: u64) -> Result<(), PaymentError> {
+    runtime
+        .notify_top_up(block_index)
+        .await
+        .map(|_| ())
+        .map_err(|e| {
+            log!(
+                INFO,
+                "[top_up] failed to notify top up for block {block_index}: {e}"
+            );
+            PaymentError::CallFailed(e)
+        })
+}
+
+/// Spawns a miner for `owner` paid by the ICP transfer in `block_index`.
+pub async fn spawn_miner<R: Runtime>(
+    runtime: &R,
+    owner: Principal,
+    block_index: u64,
+) -> Result<Principal, PaymentError> {
+    verify_payment(runtime, owner, block_index, PaymentKind::SpawnMiner).await?;
+    top_up(runtime, block_index).await?;
+
+    let canister_id = runtime
+        .create_canister(CYCLES_FOR_CREATION)
+        .await
+        .map_err(|e| {
+            log!(
+                INFO,
+                "[spawn_miner] failed to create canister for block {block_index}: {e:?}"
+            );
+            PaymentError::CallFailed(format!("{} - {:?}", e.method, e.reason))
+        })?;
+
+    runtime
+        .install_code(canister_id, miner_wasm().to_vec(), Encode!(&owner).unwrap())
+        .await
+        .map_err(|e| {
+            log!(
+                INFO,
+                "[spawn_miner] failed to install code on {canister_id}: {e:?}"
+            );
+            PaymentError::CallFailed(format!("{} - {:?}", e.method, e.reason))
+        })?;
+
+    insert_new_miner(canister_id, owner, block_index);
+    init_miner_stats(canister_id);
+    record_event(
+        EventType::MinerSpawned {
+            miner: canister_id,
+            owner,
+            block_index,
+        },
+        runtime.time(),
+    );
+
+    log!(
+        INFO,
+        "[spawn_miner] spawned miner {canister_id} for {owner} with block {block_index}"
+    );
+
+    Ok(canister_id)
+}
+
+/// Extends the pool membership of `owner` by one day per ICP transferred
+/// in `block_index`.
+pub async fn join_pool<R: Runtime>(
+    runtime: &R,
+    owner: Principal,
+    block_index: u64,
+) -> Result<(), PaymentError> {
+    let amount_e8s = verify_payment(runtime, owner, block_index, PaymentKind::JoinPool).await?;
+    top_up(runtime, block_index).await?;
+
+    let from_time = get_expiration(owner).unwrap_or(runtime.time());
+    let days = amount_e8s / E8S_PER_POOL_DAY;
+    let expiration = from_time + days * DAY_NANOS;
+    insert_expiration(owner, expiration);
+    insert_block_index(block_index);
+    record_pool_payment(owner, amount_e8s);
+    record_event(
+        EventType::PoolJoined {
+            owner,
+            block_index,
+            amount_e8s,
+            expiration,
+        },
+        runtime.time(),
+    );
+    log!(
+        INFO,
+        "[join_pool] {owner} joined the pool for {days} days with block {block_index}"
+    );
+    Ok(())
+}
+
+/// Spawns a miner for `owner` paid with the cycles attached to the call.
+/// All the attached cycles fund the new miner, they are only accepted once
+/// the canister is created so that a failed creation refunds them. The
+/// miner is registered before its code is installed: a failed install is
+/// retried by the InstallMiners task instead of leaving an orphan canister.
+pub async fn spawn_miner_with_cycles<R: Runtime>(
+    runtime: &R,
+    owner: Principal,
+) -> Result<Principal, PaymentError> {
+    let cycles = runtime.msg_cycles_available().min(u64::MAX as u128) as u64;
+    if cycles < CYCLES_FOR_CREATION {
+        return Err(PaymentError::Invalid(format!(
+            "at least {CYCLES_FOR_CREATION} cycles must be attached"
+        )));
+    }
+
+    let canister_id = runtime.create_canister(cycles).await.map_err(|e| {
+        log!(
+            INFO,
+            "[spawn_miner_with_cycles] failed to create canister with {cycles} cycles: {e:?}"
+        );
+        PaymentError::CallFailed(format!("{} - {:?}", e.method, e.reason))
+    })?;
+    runtime.msg_cycles_accept(cycles as u128);
+
+    insert_new_miner(canister_id, owner, NO_BLOCK_INDEX);
+    init_miner_stats(canister_id);
+    record_event(
+        EventType::MinerSpawnedWithCycles {
+            miner: canister_id,
+            owner,
+            cycles,
+        },
+        runtime.time(),
+    );
+
+    if let Err(e) = runtime
+        .install_code(canister_id, miner_wasm().to_vec(), Encode!(&owner).unwrap())
+        .await
+    {
+        log!(
+            INFO,
+            "[spawn_miner_with_cycles] failed to install code on {canister_id}, retrying later: {e:?}"
+        );
+        insert_pending_miner_install(canister_id, owner);
+        schedule_after(
+            Duration::from_secs(INSTALL_RETRY_INTERVAL_SECS),
+            TaskType::InstallMiners,
+        );
+        return Ok(canister_id);
+    }
+
+    log!(
+        INFO,
+        "[spawn_miner_with_cycles] spawned miner {canister_id} for {owner} with {cycles} cycles"
+    );
+
+    Ok(canister_id)
+}
+
+/// Retries the installation of the miners whose install failed when they
+/// were spawned. Returns true if some installs are still pending.
+pub async fn install_pending_miners<R: Runtime>(runtime: &R) -> bool {
+    for (miner, owner) in get_pending_miner_installs() {
+        match runtime
+            .install_code(miner, miner_wasm().to_vec(), Encode!(&owner).unwrap())
+            .await
+        {
+            Ok(()) => {
+                remove_pending_miner_install(miner);
+                log!(
+                    INFO,
+                    "[install_pending_miners] installed miner {miner} of {owner}"
+                );
+            }
+            Err(e) => log!(
+                INFO,
+                "[install_pending_miners] failed to install code on {miner}: {e:?}"
+            ),
+        }
+    }
+    !get_pending_miner_installs().is_empty()
+}
+
+/// Extends the pool membership of `owner` by one day per
+/// `CYCLES_PER_POOL_DAY` attached to the call, returns the new expiration.
+/// Cycles beyond the last whole day are refunded.
+pub fn join_pool_with_cycles<R: Runtime>(
+    runtime: &R,
+    owner: Principal,
+) -> Result<u64, PaymentError> {
+    let days = (runtime.msg_cycles_available() / CYCLES_PER_POOL_DAY as u128)
+        .min(MAX_POOL_DAYS_PER_PAYMENT as u128) as u64;
+    if days == 0 {
+        return Err(PaymentError::Invalid(format!(
+            "at least {CYCLES_PER_POOL_DAY} cycles must be attached per day"
+        )));
+    }
+    let cycles = days * CYCLES_PER_POOL_DAY;
+    runtime.msg_cycles_accept(cycles as u128);
+
+    let now = runtime.time();
+    let expiration = get_expiration(owner).unwrap_or(now).max(now) + days * DAY_NANOS;
+    insert_expiration(owner, expiration);
+    record_pool_cycles_payment(owner, cycles);
+    record_event(
+        EventType::PoolJoinedWithCycles {
+            owner,
+            cycles,
+            expiration,
+        },
+        now,
+    );
+    log!(
+        INFO,
+        "[join_pool_with_cycles] {owner} joined the pool for {days} days with {cycles} cycles"
+    );
+    Ok(expiration)
+}
+
+/// Queues a payment to be completed by the minter once its block appears.
+pub fn queue_payment(
+    owner: Principal,
+    block_index: u64,
+    kind: PaymentKind,
+    now: u64,
+) -> Result<(), String> {
+    if is_known_block(block_index) {
+        return Err("already consumed block index".to_string());
+    }
+    if get_pending_payment(block_index).is_some() {
+        return Err("a payment is already queued for this block".to_string());
+    }
+    let queued_by_owner = get_pending_payments()
+        .into_iter()
+        .filter(|(_, payment)| payment.owner == owner)
+        .count();
+    if queued_by_owner >= MAX_PENDING_PAYMENTS_PER_OWNER {
+        return Err(format!(
+            "at most {MAX_PENDING_PAYMENTS_PER_OWNER} payments can be queued"
+        ));
+    }
+    insert_pending_payment(
+        block_index,
+        PendingPayment {
+            owner,
+            kind,
+            queued_at: now,
+            attempts: 0,
+            last_error: None,
+        },
+    );
+    Ok(())
+}
+
+/// Retries the queued payments, returns true if some are still pending.
+/// Payments stay queued while their subsystem is paused.
+pub async fn complete_pending_payments<R: Runtime>(runtime: &R) -> bool {
+    for (block_index, mut payment) in get_pending_payments() {
+        if is_paused(payment.kind.subsystem()) {
+            continue;
+        }
+        // Skip owners with a request in flight for the same payment.
+        let _guard = match crate::guard::GuardPrincipal::new(payment.owner) {
+            Ok(guard) => guard,
+            Err(_) => continue,
+        };
+
+        let result = match payment.kind {
+            PaymentKind::SpawnMiner => spawn_miner(runtime, payment.owner, block_index)
+                .await
+                .map(|_| ()),
+            PaymentKind::JoinPool => join_pool(runtime, payment.owner, block_index).await,
+        };
+
+        match result {
+            Ok(()) => {
+                remove_pending_payment(block_index);
+            }
+            Err(e) if e.is_retryable() => {
+                if runtime.time() > payment.queued_at + PENDING_PAYMENT_TIMEOUT_NANOS {
+                    log!(
+                        INFO,
+                        "[complete_pending_payments] dropping the payment in block {block_index} of {}: {e}",
+                        payment.owner
+                    );
+                    remove_pending_payment(block_index);
+                } else {
+                    payment.attempts += 1;
+                    payment.last_error = Some(e.to_string());
+                    insert_pending_payment(block_index, payment);
+                }
+            }
+            Err(e) => {
+                log!(
+                    INFO,
+                    "[complete_pending_payments] rejected the payment in block {block_index} of {}: {e}",
+                    payment.owner
+                );
+                remove_pending_payment(block_index);
+            }
+        }
+    }
+    !get_pending_payments().is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{get_miner_owner, pause_subsystem, resume_subsystem};
+    use crate::runtime::mock::MockRuntime;
+    use crate::{replace_state, State};
+    use futures::executor::block_on;
+    use icp_ledger::{Memo, Tokens, Transaction};
+
+    const OWNER: Principal = Principal::from_slice(&[0x02]);
+
+    fn payment(owner: Principal, amount_e8s: u64) -> icp_ledger::Block {
+        icp_ledger::Block {
+            parent_hash: None,
+            transaction: Transaction {
+                operation: Operation::Transfer {
+                    from: AccountIdentifier::new(ic_types::PrincipalId(owner), None),
+                    to: AccountIdentifier::from_hex(MINTER_ACCOUNT).unwrap(),
+                    spender: None,
+                    amount: Tokens::from_e8s(amount_e8s),
+                    fee: Tokens::from_e8s(10_000),
+                },
+                memo: Memo(MEMO_TOP_UP_CANISTER),
+                created_at_time: None,
+                icrc1_memo: None,
+            },
+            timestamp: ic_ledger_core::timestamp::TimeStamp::from_nanos_since_unix_epoch(0),
+        }
+    }
+
+    #[test]
+    fn should_complete_queued_payments_once_the_block_appears() {
+        replace_state(State::new());
+        let runtime = MockRuntime::new(DAY_NANOS);
+        queue_payment(OWNER, 7, PaymentKind::JoinPool, runtime.time()).unwrap();
+        queue_payment(OWNER, 8, PaymentKind::SpawnMiner, runtime.time()).unwrap();
+        assert!(queue_payment(OWNER, 7, PaymentKind::JoinPool, runtime.time()).is_err());
+
+        assert!(block_on(complete_pending_payments(&runtime)));
+        assert_eq!(get_pending_payment(7).unwrap().attempts, 1);
+
+        runtime
+            .icp_blocks
+            .borrow_mut()
+            .insert(7, payment(OWNER, 200_000_000));
+        runtime
+            .icp_blocks
+            .borrow_mut()
+            .insert(8, payment(OWNER, 100_000_000));
+        assert!(!block_on(complete_pending_payments(&runtime)));
+
+        assert_eq!(get_expiration(OWNER), Some(3 * DAY_NANOS));
+        let miner = runtime.created_canisters.borrow()[0];
+        assert_eq!(get_miner_owner(miner), Some(OWNER));
+        assert!(is_known_block(7) && is_known_block(8));
+    }
+
+    #[test]
+    fn should_keep_queued_payments_while_paused() {
+        replace_state(State::new());
+        let runtime = MockRuntime::new(DAY_NANOS);
+        runtime
+            .icp_blocks
+            .borrow_mut()
+            .insert(8, payment(OWNER, 100_000_000));
+        queue_payment(OWNER, 8, PaymentKind::SpawnMiner, runtime.time()).unwrap();
+        pause_subsystem(Subsystem::Spawning);
+
+        assert!(block_on(complete_pending_payments(&runtime)));
+        assert_eq!(get_pending_payment(8).unwrap().attempts, 0);
+        assert!(runtime.created_canisters.borrow().is_empty());
+
+        resume_subsystem(Subsystem::Spawning);
+        assert!(!block_on(complete_pending_payments(&runtime)));
+        assert_eq!(runtime.created_canisters.borrow().len(), 1);
+    }
+
+    #[test]
+    fn should_cap_the_payments_queued_per_owner() {
+        replace_state(State::new());
+        for block_index in 0..MAX_PENDING_PAYMENTS_PER_OWNER as u64 {
+            queue_payment(OWNER, block_index, PaymentKind::JoinPool, 0).unwrap();
+        }
+        assert_eq!(
+            queue_payment(OWNER, 100, PaymentKind::JoinPool, 0),
+            Err(format!(
+                "at most {MAX_PENDING_PAYMENTS_PER_OWNER} payments can be queued"
+            ))
+        );
+        let other = Principal::from_slice(&[0x03]);
+        assert_eq!(queue_payment(other, 100, PaymentKind::JoinPool, 0), Ok(()));
+    }
+
+    #[test]
+    fn should_refund_the_cycles_beyond_the_last_pool_day() {
+        replace_state(State::new());
+        let runtime = MockRuntime::new(DAY_NANOS);
+        runtime
+            .attached_cycles
+            .set(2 * CYCLES_PER_POOL_DAY as u128 + 1_000);
+
+        assert_eq!(join_pool_with_cycles(&runtime, OWNER), Ok(3 * DAY_NANOS));
+        assert_eq!(
+            runtime.accepted_cycles.get(),
+            2 * CYCLES_PER_POOL_DAY as u128
+        );
+        assert_eq!(get_expiration(OWNER), Some(3 * DAY_NANOS));
+        assert!(join_pool_with_cycles(&runtime, OWNER).is_err());
+    }
+
+    #[test]
+    fn should_spawn_a_miner_funded_with_the_attached_cycles() {
+        replace_state(State::new());
+        let runtime = MockRuntime::new(DAY_NANOS);
+        runtime.attached_cycles.set(CYCLES_FOR_CREATION as u128 - 1);
+        assert!(block_on(spawn_miner_with_cycles(&runtime, OWNER)).is_err());
+        assert_eq!(runtime.accepted_cycles.get(), 0);
+
+        runtime.attached_cycles.set(2 * CYCLES_FOR_CREATION as u128);
+        let miner = block_on(spawn_miner_with_cycles(&runtime, OWNER)).unwrap();
+        assert_eq!(get_miner_owner(miner), Some(OWNER));
+        assert_eq!(
+            runtime.accepted_cycles.get(),
+            2 * CYCLES_FOR_CREATION as u128
+        );
+        assert!(!is_known_block(NO_BLOCK_INDEX));
+    }
+
+    #[test]
+    fn should_retry_the_install_of_a_spawned_miner() {
+        replace_state(State::new());
+        let runtime = MockRuntime::new(DAY_NANOS);
+        runtime.attached_cycles.set(CYCLES_FOR_CREATION as u128);
+        runtime.fail_installs.set(true);
+
+        let miner = block_on(spawn_miner_with_cycles(&runtime, OWNER)).unwrap();
+        assert_eq!(get_miner_owner(miner), Some(OWNER));
+        assert_eq!(runtime.accepted_cycles.get(), CYCLES_FOR_CREATION as u128);
+        assert!(runtime.installed_canisters.borrow().is_empty());
+        assert_eq!(get_pending_miner_installs(), vec![(miner, OWNER)]);
+
+        assert!(block_on(install_pending_miners(&runtime)));
+
+        runtime.fail_installs.set(false);
+        assert!(!block_on(install_pending_miners(&runtime)));
+        assert_eq!(runtime.installed_canisters.borrow()[0].0, miner);
+        assert!(get_pending_miner_installs().is_empty());
+    }
+
+    #[test]
+    fn should_drop_queued_payments_that_do_not_pay_the_minter() {
+        replace_state(State::new());
+        let runtime = MockRuntime::new(DAY_NANOS);
+        let other = Principal::from_slice(&[0x03]);
+        runtime
+            .icp_blocks
+            .borrow_mut()
+            .insert(7, payment(other, 100_000_000));
+        queue_payment(OWNER, 7, PaymentKind::JoinPool, runtime.time()).unwrap();
+
+        assert!(!block_on(complete_pending_payments(&runtime)));
+        assert_eq!(get_expiration(OWNER), None);
+    }
+}
@@ -0,0 +1,198 @@
+//! Payout of the blocks solved by the pool. The members are paid by a job
+//! persisted in stable memory: each run of the MineBob task transfers the
+//! rewards of a few batches of members concurrently, and the job resumes
+//! from the remaining members on the next run, including after an upgrade.
+
+use crate::events::{record_event, EventType};
+use crate::logs::INFO;
+use crate::memory::{
+    enqueue_pool_payout, get_expire_map, get_pool_payout_concurrency, get_pool_payout_job,
+    get_pool_payouts, is_claim_mode, pool_payout_queue_len, push_block, record_payout,
+    record_pool_reward, remove_block_to_mine, remove_expired_entries, remove_pool_payout,
+    set_pool_payout_job,
+};
+use crate::reconciliation::{payout_memo, Payout};
+use crate::runtime::Runtime;
+use crate::streams::{queue_extra_rewards, ExtraReward};
+use crate::vesting::{split_reward, vest_reward};
+use crate::{credit_reward, mutate_state, read_state, transfer, Block};
+use candid::{CandidType, Nat, Principal};
+use ic_canister_log::log;
+use icrc_ledger_types::icrc1::transfer::Memo;
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_POOL_PAYOUT_CONCURRENCY: u64 = 50;
+pub const MAX_POOL_PAYOUT_CONCURRENCY: u64 = 200;
+/// Number of batches processed per run, every batch takes one round of
+/// inter-canister calls.
+const MAX_BATCHES_PER_RUN: usize = 10;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub struct PoolPayoutJob {
+    pub block_timestamp: u64,
+    pub reward: u64,
+    pub started_at: u64,
+    pub member_count: u64,
+    pub processed: u64,
+    pub paid: u64,
+    /// Rewards credited to a claimable balance, either because the member
+    /// claims its rewards or because the transfer failed.
+    pub credited: u64,
+    /// Share of the secondary rewards of the block paid to each member.
+    #[serde(default)]
+    pub extra_rewards: Vec<ExtraReward>,
+}
+
+fn start_job(block: &Block, now: u64) -> PoolPayoutJob {
+    remove_expired_entries(now);
+    let members = get_expire_map();
+    for (owner, _) in members.iter() {
+        enqueue_pool_payout(*owner);
+    }
+    let member_count = members.len() as u64;
+    let job = PoolPayoutJob {
+        block_timestamp: block.timestamp,
+        reward: block.rewards.checked_div(member_count).unwrap_or(0),
+        started_at: now,
+        member_count,
+        processed: 0,
+        paid: 0,
+        credited: 0,
+        extra_rewards: block
+            .extra_rewards
+            .iter()
+            .flatten()
+            .map(|reward| ExtraReward {
+                ledger_id: reward.ledger_id,
+                amount: reward.amount.checked_div(member_count).unwrap_or(0),
+            })
+            .collect(),
+    };
+    log!(
+        INFO,
+        "[pay_pool_block] paying {} to each of the {member_count} pool members for block {}",
+        job.reward,
+        block.timestamp
+    );
+    job
+}
+
+/// Pays the share of `owner`, or credits it to its claimable balance, and
+/// only then removes the member from the job. The vested part of the share
+/// starts vesting once the rest is paid, a failed transfer credits the whole
+/// share instead.
+async fn pay_member<R: Runtime>(
+    runtime: &R,
+    job: &PoolPayoutJob,
+    owner: Principal,
+    ledger_canister_id: Principal,
+) -> bool {
+    let (reward, vested) = split_reward(job.reward);
+    let paid = if is_claim_mode(owner) {
+        credit_reward(job.block_timestamp, owner, reward, runtime.time());
+        vest_reward(job.block_timestamp, owner, vested, runtime.time());
+        false
+    } else {
+        match transfer(
+            runtime,
+            owner,
+            reward.into(),
+            Some(Nat::from(0_u8)),
+            Some(Memo::from(payout_memo(job.block_timestamp))),
+            ledger_canister_id,
+        )
+        .await
+        {
+            Ok(ledger_index) => {
+                record_payout(
+                    ledger_index,
+                    Payout {
+                        block_timestamp: job.block_timestamp,
+                        to: owner,
+                        amount: reward,
+                    },
+                );
+                record_event(
+                    EventType::RewardPaid {
+                        block_timestamp: job.block_timestamp,
+                        to: owner,
+                        amount: reward,
+                        ledger_index,
+                    },
+                    runtime.time(),
+                );
+                vest_reward(job.block_timestamp, owner, vested, runtime.time());
+                true
+            }
+            Err(e) => {
+                // The member can still claim the reward later.
+                log!(
+                    INFO,
+                    "[pay_pool_block] failed to transfer {reward} to pool member {owner}: {e:?}, credited {} to its claimable balance",
+                    job.reward
+                );
+                mutate_state(|s| s.payout_failures += 1);
+                credit_reward(job.block_timestamp, owner, job.reward, runtime.time());
+                false
+            }
+        }
+    };
+    record_pool_reward(owner, job.reward);
+    remove_pool_payout(owner);
+    paid
+}
+
+/// Pays the members for a block solved by the pool, returns true once all
+/// the members were paid and the block is recorded as mined.
+///
+/// Members are removed from the job once their share is paid or credited,
+/// so an interrupted run resumes with the members left.
+pub async fn pay_pool_block<R: Runtime>(runtime: &R, block: &Block) -> bool {
+    let mut job = match get_pool_payout_job() {
+        Some(job) if job.block_timestamp != block.timestamp => return false,
+        Some(job) => job,
+        None => start_job(block, runtime.time()),
+    };
+    set_pool_payout_job(Some(job.clone()));
+
+    let ledger_canister_id = read_state(|s| s.bob_ledger_id);
+    let concurrency = get_pool_payout_concurrency();
+    for _ in 0..MAX_BATCHES_PER_RUN {
+        let batch = get_pool_payouts(concurrency);
+        if batch.is_empty() {
+            break;
+        }
+        let results = futures::future::join_all(
+            batch
+                .iter()
+                .map(|owner| pay_member(runtime, &job, *owner, ledger_canister_id)),
+        )
+        .await;
+        for (owner, paid) in batch.iter().zip(results) {
+            queue_extra_rewards(job.block_timestamp, &job.extra_rewards, *owner, 1);
+            job.processed += 1;
+            if paid {
+                job.paid += 1;
+            } else {
+                job.credited += 1;
+            }
+        }
+        set_pool_payout_job(Some(job.clone()));
+    }
+
+    if pool_payout_queue_len() > 0 {
+        return false;
+    }
+
+    log!(
+        INFO,
+        "[pay_pool_block] paid {} and credited {} pool members for block {}",
+        job.paid,
+        job.credited,
+        block.timestamp
+    );
+    set_pool_payout_job(None);
+    remove_block_to_mine(block.clone());
+    push_block(block.clone());
+    true
+}
@@ -0,0 +1,110 @@
+use crate::SEC_NANOS;
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Number of expensive calls a principal can burst before being throttled.
+const BUCKET_CAPACITY: u64 = 5;
+/// One token is given back to each principal every minute.
+const REFILL_INTERVAL_NANOS: u64 = 60 * SEC_NANOS;
+/// Above this many tracked principals, full buckets are dropped.
+const MAX_TRACKED_PRINCIPALS: usize = 10_000;
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+pub struct TokenBucket {
+    pub tokens: u64,
+    pub last_refill: u64,
+}
+
+impl TokenBucket {
+    pub fn new(now: u64) -> Self {
+        Self {
+            tokens: BUCKET_CAPACITY,
+            last_refill: now,
+        }
+    }
+
+    fn available_tokens(&self, now: u64) -> u64 {
+        let refilled = now.saturating_sub(self.last_refill) / REFILL_INTERVAL_NANOS;
+        self.tokens.saturating_add(refilled).min(BUCKET_CAPACITY)
+    }
+
+    fn refill(&mut self, now: u64) {
+        let refilled = now.saturating_sub(self.last_refill) / REFILL_INTERVAL_NANOS;
+        if refilled > 0 {
+            self.tokens = self.tokens.saturating_add(refilled).min(BUCKET_CAPACITY);
+            self.last_refill += refilled * REFILL_INTERVAL_NANOS;
+        }
+    }
+
+    pub fn try_acquire(&mut self, now: u64) -> bool {
+        self.refill(now);
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+
+    pub fn is_full(&self, now: u64) -> bool {
+        self.available_tokens(now) == BUCKET_CAPACITY
+    }
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+pub struct RateLimiter {
+    buckets: BTreeMap<Principal, TokenBucket>,
+}
+
+impl RateLimiter {
+    /// Returns true if the principal has at least one token left, without
+    /// consuming it.
+    pub fn has_capacity(&self, principal: &Principal, now: u64) -> bool {
+        self.buckets
+            .get(principal)
+            .map(|bucket| bucket.available_tokens(now) > 0)
+            .unwrap_or(true)
+    }
+
+    /// Consumes one token from the principal's bucket.
+    pub fn try_acquire(&mut self, principal: Principal, now: u64) -> Result<(), String> {
+        if self.buckets.len() >= MAX_TRACKED_PRINCIPALS {
+            self.buckets.retain(|_, bucket| !bucket.is_full(now));
+        }
+        let bucket = self
+            .buckets
+            .entry(principal)
+            .or_insert_with(|| TokenBucket::new(now));
+        if !bucket.try_acquire(now) {
+            return Err("rate limit exceeded, retry in a minute".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_throttle_after_burst_and_refill_over_time() {
+        let caller = Principal::from_slice(&[0xFF; 29]);
+        let mut limiter = RateLimiter::default();
+
+        for _ in 0..BUCKET_CAPACITY {
+            assert_eq!(limiter.try_acquire(caller, 0), Ok(()));
+        }
+        assert!(!limiter.has_capacity(&caller, 0));
+        assert!(limiter.try_acquire(caller, 0).is_err());
+
+        assert!(limiter.has_capacity(&caller, REFILL_INTERVAL_NANOS));
+        assert_eq!(limiter.try_acquire(caller, REFILL_INTERVAL_NANOS), Ok(()));
+        assert!(limiter.try_acquire(caller, REFILL_INTERVAL_NANOS).is_err());
+
+        let later = 100 * REFILL_INTERVAL_NANOS;
+        for _ in 0..BUCKET_CAPACITY {
+            assert_eq!(limiter.try_acquire(caller, later), Ok(()));
+        }
+        assert!(limiter.try_acquire(caller, later).is_err());
+    }
+}
@@ -0,0 +1,567 @@
+//! Periodic reconciliation between the payouts recorded by the minter and
+//! the mints found in the BOB ledger.
+//!
+//! Every reward transfer carries the timestamp of the block it pays as its
+//! memo, which lets the reconciliation detect a block paid twice to the same
+//! owner even when the minter never recorded the second transfer.
+
+use crate::logs::{DEBUG, INFO};
+use crate::memory::{
+    get_payout, get_reconciliation_state, min_payout_ledger_index, push_discrepancy,
+    record_seen_mint, set_reconciliation_state,
+};
+use crate::read_state;
+use candid::{CandidType, Nat, Principal};
+use ic_canister_log::log;
+use icrc_ledger_types::icrc::generic_value::ICRC3Value;
+use icrc_ledger_types::icrc3::blocks::{GetBlocksRequest, GetBlocksResult};
+use serde::{Deserialize, Serialize};
+
+/// Maximum number of ledger blocks requested per call.
+const MAX_BLOCKS_PER_REQUEST: u64 = 100;
+/// Maximum number of requests per reconciliation run.
+const MAX_REQUESTS_PER_RUN: u64 = 10;
+
+/// A reward transfer made by the minter, keyed by its ledger index.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub struct Payout {
+    pub block_timestamp: u64,
+    pub to: Principal,
+    pub amount: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub enum Discrepancy {
+    /// A recorded payout has no matching mint at its ledger index.
+    MissingPayout { ledger_index: u64, payout: Payout },
+    /// The same block was minted twice to the same owner.
+    DoublePayout {
+        ledger_index: u64,
+        first_ledger_index: u64,
+        block_timestamp: u64,
+        to: Principal,
+        amount: u64,
+    },
+    /// A mint that cannot be traced back to any block.
+    UnexpectedMint {
+        ledger_index: u64,
+        to: Principal,
+        amount: u64,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub struct DiscrepancyRecord {
+    pub detected_at: u64,
+    pub discrepancy: Discrepancy,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub struct ReconciliationState {
+    /// Next ledger block to check, unset until the first run.
+    pub next_ledger_block: Option<u64>,
+    pub ledger_length: u64,
+    pub last_run_ts: u64,
+    pub checked_mints: u64,
+    pub matched_payouts: u64,
+    pub missing_payouts: u64,
+    pub double_payouts: u64,
+    pub unexpected_mints: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct LedgerMint {
+    to: Principal,
+    amount: u64,
+    memo: Option<Vec<u8>>,
+}
+
+/// Encodes the memo attached to the reward transfers of a block.
+pub fn payout_memo(block_timestamp: u64) -> Vec<u8> {
+    block_timestamp.to_be_bytes().to_vec()
+}
+
+fn block_timestamp_from_memo(memo: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(memo.try_into().ok()?))
+}
+
+fn parse_mint(block: &ICRC3Value) -> Option<LedgerMint> {
+    let ICRC3Value::Map(block) = block else {
+        return None;
+    };
+    let Some(ICRC3Value::Map(tx)) = block.get("tx") else {
+        return None;
+    };
+    let is_mint = matches!(tx.get("op"), Some(ICRC3Value::Text(op)) if op == "mint")
+        || matches!(block.get("btype"), Some(ICRC3Value::Text(btype)) if btype == "1mint");
+    if !is_mint {
+        return None;
+    }
+    let to = match tx.get("to") {
+        Some(ICRC3Value::Array(account)) => match account.first() {
+            Some(ICRC3Value::Blob(owner)) => Principal::try_from_slice(owner).ok()?,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let amount = match tx.get("amt") {
+        Some(ICRC3Value::Nat(amount)) => u64::try_from(amount.0.clone()).ok()?,
+        _ => return None,
+    };
+    let memo = match tx.get("memo") {
+        Some(ICRC3Value::Blob(memo)) => Some(memo.to_vec()),
+        _ => None,
+    };
+    Some(LedgerMint { to, amount, memo })
+}
+
+async fn get_ledger_blocks(
+    canister_id: Principal,
+    method: &str,
+    args: Vec<GetBlocksRequest>,
+) -> Result<GetBlocksResult, String> {
+    let result: Result<(GetBlocksResult,), _> =
+        ic_cdk::api::call::call(canister_id, method, (args,)).await;
+    match result {
+        Ok((res,)) => Ok(res),
+        Err((code, msg)) => Err(format!(
+            "Error while calling ledger canister ({}): {:?}",
+            code as i32, msg
+        )),
+    }
+}
+
+/// Fetches up to `length` blocks starting at `start`, following archive
+/// callbacks. Returns the ledger length and the contiguous blocks found.
+async fn fetch_ledger_blocks(
+    ledger_id: Principal,
+    start: u64,
+    length: u64,
+) -> Result<(u64, Vec<(u64, ICRC3Value)>), String> {
+    let request = GetBlocksRequest {
+        start: Nat::from(start),
+        length: Nat::from(length),
+    };
+    let result = get_ledger_blocks(ledger_id, "icrc3_get_blocks", vec![request]).await?;
+    let ledger_length: u64 = result
+        .log_length
+        .0
+        .try_into()
+        .map_err(|_| "ledger length does not fit in u64".to_string())?;
+
+    let mut blocks: Vec<(Nat, ICRC3Value)> = result
+        .blocks
+        .into_iter()
+        .map(|block| (block.id, block.block))
+        .collect();
+
+    for archived in result.archived_blocks {
+        let archived_result = get_ledger_blocks(
+            archived.callback.canister_id,
+            &archived.callback.method,
+            archived.args,
+        )
+        .await?;
+        blocks.extend(
+            archived_result
+                .blocks
+                .into_iter()
+                .map(|block| (block.id, block.block)),
+        );
+    }
+
+    let mut blocks: Vec<(u64, ICRC3Value)> = blocks
+        .into_iter()
+        .filter_map(|(id, block)| Some((u64::try_from(id.0).ok()?, block)))
+        .collect();
+    blocks.sort_by_key(|(id, _)| *id);
+
+    let mut contiguous = vec![];
+    let mut expected = start;
+    for (id, block) in blocks {
+        if id != expected {
+            break;
+        }
+        contiguous.push((id, block));
+        expected += 1;
+    }
+    Ok((ledger_length, contiguous))
+}
+
+fn report(state: &mut ReconciliationState, discrepancy: Discrepancy, now: u64) {
+    log!(INFO, "[reconcile] found discrepancy: {discrepancy:?}");
+    match discrepancy {
+        Discrepancy::MissingPayout { .. } => state.missing_payouts += 1,
+        Discrepancy::DoublePayout { .. } => state.double_payouts += 1,
+        Discrepancy::UnexpectedMint { .. } => state.unexpected_mints += 1,
+    }
+    push_discrepancy(DiscrepancyRecord {
+        detected_at: now,
+        discrepancy,
+    });
+}
+
+/// Checks a mint for which the minter did not record a payout, or whose
+/// recorded payout did not match it.
+fn check_unrecorded_mint(
+    state: &mut ReconciliationState,
+    ledger_index: u64,
+    mint: LedgerMint,
+    now: u64,
+) {
+    match mint.memo.as_deref().and_then(block_timestamp_from_memo) {
+        Some(block_timestamp) => {
+            if let Some(first_ledger_index) =
+                record_seen_mint(block_timestamp, mint.to, ledger_index)
+            {
+                report(
+                    state,
+                    Discrepancy::DoublePayout {
+                        ledger_index,
+                        first_ledger_index,
+                        block_timestamp,
+                        to: mint.to,
+                        amount: mint.amount,
+                    },
+                    now,
+                );
+            }
+        }
+        None => report(
+            state,
+            Discrepancy::UnexpectedMint {
+                ledger_index,
+                to: mint.to,
+                amount: mint.amount,
+            },
+            now,
+        ),
+    }
+}
+
+fn reconcile_block(
+    state: &mut ReconciliationState,
+    ledger_index: u64,
+    block: &ICRC3Value,
+    now: u64,
+) {
+    let maybe_mint = parse_mint(block);
+    if maybe_mint.is_some() {
+        state.checked_mints += 1;
+    }
+
+    match (maybe_mint, get_payout(ledger_index)) {
+        (Some(mint), Some(payout)) => {
+            if mint.to == payout.to && mint.amount == payout.amount {
+                match record_seen_mint(payout.block_timestamp, payout.to, ledger_index) {
+                    Some(first_ledger_index) => report(
+                        state,
+                        Discrepancy::DoublePayout {
+                            ledger_index,
+                            first_ledger_index,
+                            block_timestamp: payout.block_timestamp,
+                            to: payout.to,
+                            amount: payout.amount,
+                        },
+                        now,
+                    ),
+                    None => state.matched_payouts += 1,
+                }
+            } else {
+                report(
+                    state,
+                    Discrepancy::MissingPayout {
+                        ledger_index,
+                        payout,
+                    },
+                    now,
+                );
+                check_unrecorded_mint(state, ledger_index, mint, now);
+            }
+        }
+        (None, Some(payout)) => report(
+            state,
+            Discrepancy::MissingPayout {
+                ledger_index,
+                payout,
+            },
+            now,
+        ),
+        (Some(mint), None) => check_unrecorded_mint(state, ledger_index, mint, now),
+        (None, None) => {}
+    }
+}
+
+/// Walks the new blocks of the BOB ledger and compares its mints against
+/// the recorded payouts. Returns true once the ledger tip is reached.
+pub async fn reconcile() -> Result<bool, String> {
+    let ledger_id = read_state(|s| s.bob_ledger_id);
+    let mut state = get_reconciliation_state();
+
+    let mut next_block = match state.next_ledger_block {
+        Some(next_block) => next_block,
+        None => match min_payout_ledger_index() {
+            Some(index) => index,
+            None => fetch_ledger_blocks(ledger_id, 0, 0).await?.0,
+        },
+    };
+
+    for _ in 0..MAX_REQUESTS_PER_RUN {
+        let (ledger_length, blocks) =
+            fetch_ledger_blocks(ledger_id, next_block, MAX_BLOCKS_PER_REQUEST).await?;
+        state.ledger_length = ledger_length;
+
+        let now = ic_cdk::api::time();
+        for (ledger_index, block) in blocks.iter() {
+            reconcile_block(&mut state, *ledger_index, block, now);
+        }
+        next_block += blocks.len() as u64;
+
+        state.next_ledger_block = Some(next_block);
+        state.last_run_ts = now;
+        set_reconciliation_state(state.clone());
+
+        if blocks.is_empty() || next_block >= ledger_length {
+            log!(
+                DEBUG,
+                "[reconcile] reconciled the BOB ledger up to block {next_block}"
+            );
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{discrepancy_count, get_discrepancy, record_payout};
+    use serde_bytes::ByteBuf;
+    use std::collections::BTreeMap;
+
+    const OWNER: Principal = Principal::from_slice(&[0x02]);
+    const BLOCK_TS: u64 = 42;
+
+    fn blob(bytes: &[u8]) -> ICRC3Value {
+        ICRC3Value::Blob(ByteBuf::from(bytes.to_vec()))
+    }
+
+    fn text(text: &str) -> ICRC3Value {
+        ICRC3Value::Text(text.to_string())
+    }
+
+    fn ledger_block(tx: Vec<(&str, ICRC3Value)>) -> ICRC3Value {
+        let tx = tx.into_iter().map(|(k, v)| (k.to_string(), v)).collect();
+        ICRC3Value::Map(BTreeMap::from([("tx".to_string(), ICRC3Value::Map(tx))]))
+    }
+
+    fn mint(to: Principal, amount: u64, memo: Option<Vec<u8>>) -> ICRC3Value {
+        let mut tx = vec![
+            ("op", text("mint")),
+            ("to", ICRC3Value::Array(vec![blob(to.as_slice())])),
+            ("amt", ICRC3Value::Nat(Nat::from(amount))),
+        ];
+        if let Some(memo) = memo {
+            tx.push(("memo", blob(&memo)));
+        }
+        ledger_block(tx)
+    }
+
+    fn payout(amount: u64) -> Payout {
+        Payout {
+            block_timestamp: BLOCK_TS,
+            to: OWNER,
+            amount,
+        }
+    }
+
+    fn discrepancies() -> Vec<Discrepancy> {
+        (0..discrepancy_count())
+            .filter_map(get_discrepancy)
+            .map(|record| record.discrepancy)
+            .collect()
+    }
+
+    #[test]
+    fn should_parse_mints() {
+        assert_eq!(
+            parse_mint(&mint(OWNER, 100, Some(payout_memo(BLOCK_TS)))),
+            Some(LedgerMint {
+                to: OWNER,
+                amount: 100,
+                memo: Some(payout_memo(BLOCK_TS)),
+            })
+        );
+
+        let ICRC3Value::Map(mut block) = mint(OWNER, 100, None) else {
+            unreachable!()
+        };
+        let Some(ICRC3Value::Map(tx)) = block.get_mut("tx") else {
+            unreachable!()
+        };
+        tx.remove("op");
+        block.insert("btype".to_string(), text("1mint"));
+        assert_eq!(
+            parse_mint(&ICRC3Value::Map(block)).map(|mint| mint.amount),
+            Some(100)
+        );
+    }
+
+    #[test]
+    fn should_ignore_malformed_blocks() {
+        let to = ICRC3Value::Array(vec![blob(OWNER.as_slice())]);
+        let amt = ICRC3Value::Nat(Nat::from(100_u64));
+        let malformed = vec![
+            text("mint"),
+            ICRC3Value::Map(BTreeMap::new()),
+            ledger_block(vec![
+                ("op", text("xfer")),
+                ("to", to.clone()),
+                ("amt", amt.clone()),
+            ]),
+            ledger_block(vec![("op", text("mint")), ("amt", amt.clone())]),
+            ledger_block(vec![
+                ("op", text("mint")),
+                ("to", blob(OWNER.as_slice())),
+                ("amt", amt.clone()),
+            ]),
+            ledger_block(vec![
+                ("op", text("mint")),
+                ("to", ICRC3Value::Array(vec![blob(&[0; 30])])),
+                ("amt", amt.clone()),
+            ]),
+            ledger_block(vec![
+                ("op", text("mint")),
+                ("to", to.clone()),
+                ("amt", text("100")),
+            ]),
+            ledger_block(vec![
+                ("op", text("mint")),
+                ("to", to),
+                ("amt", ICRC3Value::Nat(Nat::from(u128::MAX))),
+            ]),
+        ];
+
+        let mut state = ReconciliationState::default();
+        for (ledger_index, block) in malformed.iter().enumerate() {
+            assert_eq!(parse_mint(block), None);
+            reconcile_block(&mut state, ledger_index as u64, block, 0);
+        }
+        assert_eq!(state, ReconciliationState::default());
+        assert!(discrepancies().is_empty());
+    }
+
+    #[test]
+    fn should_match_recorded_payouts() {
+        record_payout(5, payout(100));
+        let mut state = ReconciliationState::default();
+
+        reconcile_block(
+            &mut state,
+            5,
+            &mint(OWNER, 100, Some(payout_memo(BLOCK_TS))),
+            0,
+        );
+
+        assert_eq!(state.checked_mints, 1);
+        assert_eq!(state.matched_payouts, 1);
+        assert!(discrepancies().is_empty());
+    }
+
+    #[test]
+    fn should_report_missing_payouts() {
+        record_payout(5, payout(100));
+        record_payout(6, payout(100));
+        let mut state = ReconciliationState::default();
+
+        reconcile_block(&mut state, 5, &text("not a block"), 0);
+        reconcile_block(
+            &mut state,
+            6,
+            &mint(OWNER, 99, Some(payout_memo(BLOCK_TS))),
+            0,
+        );
+
+        assert_eq!(state.missing_payouts, 2);
+        assert_eq!(state.double_payouts, 0);
+        assert_eq!(
+            discrepancies(),
+            vec![
+                Discrepancy::MissingPayout {
+                    ledger_index: 5,
+                    payout: payout(100),
+                },
+                Discrepancy::MissingPayout {
+                    ledger_index: 6,
+                    payout: payout(100),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn should_report_double_payouts() {
+        record_payout(5, payout(100));
+        let mut state = ReconciliationState::default();
+
+        reconcile_block(
+            &mut state,
+            5,
+            &mint(OWNER, 100, Some(payout_memo(BLOCK_TS))),
+            0,
+        );
+        reconcile_block(
+            &mut state,
+            8,
+            &mint(OWNER, 100, Some(payout_memo(BLOCK_TS))),
+            0,
+        );
+
+        assert_eq!(state.matched_payouts, 1);
+        assert_eq!(state.double_payouts, 1);
+        assert_eq!(
+            discrepancies(),
+            vec![Discrepancy::DoublePayout {
+                ledger_index: 8,
+                first_ledger_index: 5,
+                block_timestamp: BLOCK_TS,
+                to: OWNER,
+                amount: 100,
+            }]
+        );
+    }
+
+    #[test]
+    fn should_report_unexpected_mints() {
+        let mut state = ReconciliationState::default();
+
+        reconcile_block(&mut state, 3, &mint(OWNER, 100, None), 0);
+        reconcile_block(&mut state, 4, &mint(OWNER, 100, Some(vec![1, 2, 3])), 0);
+        // An unrecorded mint of a block paid once is not a discrepancy.
+        reconcile_block(
+            &mut state,
+            5,
+            &mint(OWNER, 100, Some(payout_memo(BLOCK_TS))),
+            0,
+        );
+
+        assert_eq!(state.checked_mints, 3);
+        assert_eq!(state.unexpected_mints, 2);
+        assert_eq!(
+            discrepancies(),
+            vec![
+                Discrepancy::UnexpectedMint {
+                    ledger_index: 3,
+                    to: OWNER,
+                    amount: 100,
+                },
+                Discrepancy::UnexpectedMint {
+                    ledger_index: 4,
+                    to: OWNER,
+                    amount: 100,
+                },
+            ]
+        );
+    }
+}
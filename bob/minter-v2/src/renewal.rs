@@ -0,0 +1,374 @@
+//! Automatic renewal of pool memberships. Members approve an ICP allowance
+//! for the minter, which charges it shortly before the membership expires,
+//! converts the ICP to cycles and extends the membership.
+
+use crate::events::{record_event, EventType};
+use crate::logs::{DEBUG, INFO};
+use crate::memory::{
+    get_auto_renewal, get_auto_renewals, get_expiration, get_pending_top_ups, insert_block_index,
+    insert_expiration, insert_pending_top_up, push_renewal_record, record_pool_payment,
+    remove_auto_renewal, remove_pending_top_up, set_auto_renewal,
+};
+use crate::runtime::Runtime;
+use crate::{
+    DAY_NANOS, E8S_PER_POOL_DAY, MAINNET_CYCLE_MINTER_CANISTER_ID, MAINNET_LEDGER_CANISTER_ID,
+    MEMO_TOP_UP_CANISTER, SEC_NANOS,
+};
+use candid::{CandidType, Nat, Principal};
+use ic_canister_log::log;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::Memo;
+use icrc_ledger_types::icrc2::transfer_from::TransferFromArgs;
+use serde::{Deserialize, Serialize};
+
+pub const MAX_RENEWAL_PERIOD_DAYS: u64 = 30;
+/// Memberships are renewed when they expire within this window.
+const RENEWAL_WINDOW_NANOS: u64 = 60 * 60 * SEC_NANOS;
+/// Delay before retrying a failed renewal.
+const RETRY_DELAY_NANOS: u64 = 30 * 60 * SEC_NANOS;
+/// Auto-renewal is disabled after this many consecutive failures.
+const MAX_CONSECUTIVE_FAILURES: u64 = 3;
+/// Maximum number of renewals charged per run.
+const MAX_RENEWALS_PER_RUN: usize = 20;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub struct AutoRenewal {
+    pub period_days: u64,
+    pub enabled_at: u64,
+    pub last_attempt_ts: Option<u64>,
+    pub consecutive_failures: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub enum RenewalOutcome {
+    Renewed {
+        ledger_index: u64,
+        expiration: u64,
+        notify_error: Option<String>,
+    },
+    Failed(String),
+    Cancelled,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub struct RenewalRecord {
+    pub owner: Principal,
+    pub timestamp: u64,
+    pub period_days: u64,
+    pub amount_e8s: u64,
+    pub outcome: RenewalOutcome,
+}
+
+pub fn enable_auto_renewal(owner: Principal, period_days: u64, now: u64) -> Result<(), String> {
+    if period_days == 0 || period_days > MAX_RENEWAL_PERIOD_DAYS {
+        return Err(format!(
+            "the renewal period must be between 1 and {MAX_RENEWAL_PERIOD_DAYS} days"
+        ));
+    }
+    if get_expiration(owner).is_none() {
+        return Err("only pool members can enable auto-renewal".to_string());
+    }
+    set_auto_renewal(
+        owner,
+        AutoRenewal {
+            period_days,
+            enabled_at: now,
+            last_attempt_ts: None,
+            consecutive_failures: 0,
+        },
+    );
+    Ok(())
+}
+
+pub fn cancel_auto_renewal(owner: Principal, now: u64) -> Result<(), String> {
+    let renewal = remove_auto_renewal(owner).ok_or("auto-renewal is not enabled")?;
+    push_renewal_record(RenewalRecord {
+        owner,
+        timestamp: now,
+        period_days: renewal.period_days,
+        amount_e8s: 0,
+        outcome: RenewalOutcome::Cancelled,
+    });
+    Ok(())
+}
+
+fn is_due(owner: Principal, renewal: &AutoRenewal, now: u64) -> bool {
+    if let Some(last_attempt_ts) = renewal.last_attempt_ts {
+        if renewal.consecutive_failures > 0 && now < last_attempt_ts + RETRY_DELAY_NANOS {
+            return false;
+        }
+    }
+    // Memberships that lapsed and were removed are not renewed.
+    get_expiration(owner).is_some_and(|expiration| expiration <= now + RENEWAL_WINDOW_NANOS)
+}
+
+/// Account of the minter at the cycles minting canister, see `join_pool`.
+fn top_up_account(minter_id: Principal) -> Account {
+    let subaccount = icp_ledger::Subaccount::from(&ic_types::PrincipalId(minter_id));
+    Account {
+        owner: MAINNET_CYCLE_MINTER_CANISTER_ID,
+        subaccount: Some(subaccount.0),
+    }
+}
+
+async fn renew<R: Runtime>(runtime: &R, owner: Principal, renewal: &AutoRenewal) -> RenewalRecord {
+    let now = runtime.time();
+    let amount_e8s = renewal.period_days * E8S_PER_POOL_DAY;
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account::from(owner),
+        to: top_up_account(runtime.canister_id()),
+        amount: Nat::from(amount_e8s),
+        fee: None,
+        memo: Some(Memo::from(MEMO_TOP_UP_CANISTER.to_le_bytes().to_vec())),
+        created_at_time: None,
+    };
+
+    let outcome = match runtime
+        .icrc2_transfer_from(MAINNET_LEDGER_CANISTER_ID, args)
+        .await
+    {
+        Ok(ledger_index) => {
+            insert_block_index(ledger_index);
+            let notify_error = runtime
+                .notify_top_up(ledger_index)
+                .await
+                .err()
+                .inspect(|e| {
+                    log!(
+                        INFO,
+                        "[renew] failed to notify top up for block {ledger_index}: {e}"
+                    );
+                    insert_pending_top_up(ledger_index);
+                });
+            let from_time = get_expiration(owner).unwrap_or(now).max(now);
+            let expiration = from_time + renewal.period_days * DAY_NANOS;
+            insert_expiration(owner, expiration);
+            record_pool_payment(owner, amount_e8s);
+            record_event(
+                EventType::PoolJoined {
+                    owner,
+                    block_index: ledger_index,
+                    amount_e8s,
+                    expiration,
+                },
+                now,
+            );
+            RenewalOutcome::Renewed {
+                ledger_index,
+                expiration,
+                notify_error,
+            }
+        }
+        Err(e) => RenewalOutcome::Failed(format!("{e:?}")),
+    };
+
+    RenewalRecord {
+        owner,
+        timestamp: now,
+        period_days: renewal.period_days,
+        amount_e8s,
+        outcome,
+    }
+}
+
+/// Notifies the cycles minting canister of the renewals whose notification
+/// failed, their ICP is not converted to cycles otherwise.
+async fn retry_pending_top_ups<R: Runtime>(runtime: &R) {
+    for block_index in get_pending_top_ups() {
+        match runtime.notify_top_up(block_index).await {
+            Ok(_) => {
+                remove_pending_top_up(block_index);
+                log!(
+                    INFO,
+                    "[retry_pending_top_ups] notified top up for block {block_index}"
+                );
+            }
+            Err(e) => log!(
+                INFO,
+                "[retry_pending_top_ups] failed to notify top up for block {block_index}: {e}"
+            ),
+        }
+    }
+}
+
+/// Charges the members whose membership is about to expire and retries
+/// the top ups that were not notified.
+pub async fn renew_memberships<R: Runtime>(runtime: &R) {
+    retry_pending_top_ups(runtime).await;
+
+    let now = runtime.time();
+    let due: Vec<(Principal, AutoRenewal)> = get_auto_renewals()
+        .into_iter()
+        .filter(|(owner, renewal)| is_due(*owner, renewal, now))
+        .take(MAX_RENEWALS_PER_RUN)
+        .collect();
+
+    for (owner, renewal) in due {
+        let record = renew(runtime, owner, &renewal).await;
+
+        // The member may have cancelled while the transfer was in flight.
+        if let Some(mut renewal) = get_auto_renewal(owner) {
+            renewal.last_attempt_ts = Some(record.timestamp);
+            match &record.outcome {
+                RenewalOutcome::Failed(error) => {
+                    renewal.consecutive_failures += 1;
+                    log!(
+                        INFO,
+                        "[renew_memberships] failed to renew the membership of {owner}: {error}"
+                    );
+                    if renewal.consecutive_failures >= MAX_CONSECUTIVE_FAILURES {
+                        log!(
+                            INFO,
+                            "[renew_memberships] disabling auto-renewal of {owner} after {} failures",
+                            renewal.consecutive_failures
+                        );
+                        remove_auto_renewal(owner);
+                    } else {
+                        set_auto_renewal(owner, renewal);
+                    }
+                }
+                _ => {
+                    renewal.consecutive_failures = 0;
+                    log!(
+                        DEBUG,
+                        "[renew_memberships] renewed the membership of {owner} for {} days",
+                        renewal.period_days
+                    );
+                    set_auto_renewal(owner, renewal);
+                }
+            }
+        }
+        push_renewal_record(record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{get_renewal_record, renewal_log_len};
+    use crate::runtime::mock::{MockRuntime, CANISTER_ID};
+    use futures::executor::block_on;
+
+    const OWNER: Principal = Principal::from_slice(&[0x02]);
+
+    fn setup(expiration: u64) -> MockRuntime {
+        let runtime = MockRuntime::new(DAY_NANOS);
+        insert_expiration(OWNER, expiration);
+        enable_auto_renewal(OWNER, 2, runtime.time()).unwrap();
+        runtime
+    }
+
+    fn last_outcome() -> RenewalOutcome {
+        get_renewal_record(renewal_log_len() - 1).unwrap().outcome
+    }
+
+    #[test]
+    fn should_only_enable_auto_renewal_for_members() {
+        assert!(enable_auto_renewal(OWNER, 1, 0).is_err());
+        insert_expiration(OWNER, DAY_NANOS);
+        assert!(enable_auto_renewal(OWNER, 0, 0).is_err());
+        assert!(enable_auto_renewal(OWNER, MAX_RENEWAL_PERIOD_DAYS + 1, 0).is_err());
+        assert_eq!(enable_auto_renewal(OWNER, 1, 0), Ok(()));
+    }
+
+    #[test]
+    fn should_only_charge_memberships_about_to_expire() {
+        let runtime = setup(DAY_NANOS + 2 * RENEWAL_WINDOW_NANOS);
+        let renewal = get_auto_renewal(OWNER).unwrap();
+        assert!(!is_due(OWNER, &renewal, runtime.time()));
+        assert!(!is_due(Principal::anonymous(), &renewal, runtime.time()));
+
+        block_on(renew_memberships(&runtime));
+        assert!(runtime.transfers_from.borrow().is_empty());
+        assert_eq!(renewal_log_len(), 0);
+
+        runtime.time.set(DAY_NANOS + RENEWAL_WINDOW_NANOS);
+        assert!(is_due(OWNER, &renewal, runtime.time()));
+    }
+
+    #[test]
+    fn should_charge_the_renewal_period_and_extend_the_membership() {
+        let expiration = DAY_NANOS + RENEWAL_WINDOW_NANOS / 2;
+        let runtime = setup(expiration);
+
+        block_on(renew_memberships(&runtime));
+
+        let transfers_from = runtime.transfers_from.borrow();
+        assert_eq!(transfers_from.len(), 1);
+        let (ledger_id, args) = &transfers_from[0];
+        assert_eq!(*ledger_id, MAINNET_LEDGER_CANISTER_ID);
+        assert_eq!(args.from, Account::from(OWNER));
+        assert_eq!(args.to, top_up_account(CANISTER_ID));
+        assert_eq!(args.amount, Nat::from(2 * E8S_PER_POOL_DAY));
+        assert_eq!(*runtime.notified_blocks.borrow(), vec![0]);
+
+        let new_expiration = expiration + 2 * DAY_NANOS;
+        assert_eq!(get_expiration(OWNER), Some(new_expiration));
+        assert_eq!(
+            last_outcome(),
+            RenewalOutcome::Renewed {
+                ledger_index: 0,
+                expiration: new_expiration,
+                notify_error: None,
+            }
+        );
+        assert_eq!(get_auto_renewal(OWNER).unwrap().consecutive_failures, 0);
+    }
+
+    #[test]
+    fn should_retry_failed_top_up_notifications() {
+        let runtime = setup(DAY_NANOS);
+        runtime.fail_notifications.set(true);
+
+        block_on(renew_memberships(&runtime));
+        assert!(matches!(
+            last_outcome(),
+            RenewalOutcome::Renewed {
+                notify_error: Some(_),
+                ..
+            }
+        ));
+        assert_eq!(get_pending_top_ups(), vec![0]);
+
+        runtime.fail_notifications.set(false);
+        block_on(renew_memberships(&runtime));
+        assert!(get_pending_top_ups().is_empty());
+        assert_eq!(*runtime.notified_blocks.borrow(), vec![0]);
+    }
+
+    #[test]
+    fn should_disable_auto_renewal_after_repeated_failures() {
+        let runtime = setup(DAY_NANOS);
+        runtime.fail_transfers.set(true);
+
+        block_on(renew_memberships(&runtime));
+        assert!(matches!(last_outcome(), RenewalOutcome::Failed(_)));
+        assert_eq!(get_auto_renewal(OWNER).unwrap().consecutive_failures, 1);
+
+        // Failed renewals are only retried after a delay.
+        block_on(renew_memberships(&runtime));
+        assert_eq!(renewal_log_len(), 1);
+
+        for _ in 1..MAX_CONSECUTIVE_FAILURES {
+            runtime.time.set(runtime.time() + RETRY_DELAY_NANOS);
+            block_on(renew_memberships(&runtime));
+        }
+        assert_eq!(renewal_log_len(), MAX_CONSECUTIVE_FAILURES);
+        assert_eq!(get_auto_renewal(OWNER), None);
+        assert_eq!(get_expiration(OWNER), Some(DAY_NANOS));
+    }
+
+    #[test]
+    fn should_not_charge_after_cancellation() {
+        let runtime = setup(DAY_NANOS);
+
+        assert_eq!(cancel_auto_renewal(OWNER, runtime.time()), Ok(()));
+        assert_eq!(last_outcome(), RenewalOutcome::Cancelled);
+        assert!(cancel_auto_renewal(OWNER, runtime.time()).is_err());
+
+        block_on(renew_memberships(&runtime));
+        assert!(runtime.transfers_from.borrow().is_empty());
+        assert_eq!(renewal_log_len(), 1);
+    }
+}
@@ -0,0 +1,324 @@
+//! System APIs and inter-canister calls used by the minter logic, behind a
+//! trait so that the logic can run against an in-memory mock in unit tests.
+
+use crate::estimator::IcpXdrConversionRate;
+use crate::miner::CallError;
+use crate::payment::PaymentError;
+use candid::{Nat, Principal};
+use ic_types::Cycles;
+use icrc_ledger_client_cdk::ICRC1Client;
+use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
+use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
+
+#[allow(async_fn_in_trait)]
+pub trait Runtime {
+    /// Current time in nanoseconds since the epoch.
+    fn time(&self) -> u64;
+
+    /// Principal of the minter canister.
+    fn canister_id(&self) -> Principal;
+
+    /// 32 random bytes from the management canister.
+    async fn raw_rand(&self) -> Result<[u8; 32], String>;
+
+    /// Burns up to `amount` cycles, returns the amount actually burned.
+    fn cycles_burn(&self, amount: u128) -> u128;
+
+    /// Cycles attached to the call and not accepted yet.
+    fn msg_cycles_available(&self) -> u128;
+
+    /// Accepts up to `max_amount` of the cycles attached to the call.
+    fn msg_cycles_accept(&self, max_amount: u128) -> u128;
+
+    async fn icrc1_transfer(
+        &self,
+        ledger_canister_id: Principal,
+        arg: TransferArg,
+    ) -> Result<u64, TransferError>;
+
+    async fn icrc2_transfer_from(
+        &self,
+        ledger_canister_id: Principal,
+        args: TransferFromArgs,
+    ) -> Result<u64, TransferFromError>;
+
+    /// Fetches an ICP ledger block from the ICP index or the ICP ledger.
+    async fn fetch_icp_block(&self, block_index: u64) -> Result<icp_ledger::Block, PaymentError>;
+
+    /// Converts the ICP sent in the given block to cycles for the minter.
+    async fn notify_top_up(&self, block_index: u64) -> Result<Cycles, String>;
+
+    async fn create_canister(
+        &self,
+        cycles_for_canister_creation: u64,
+    ) -> Result<Principal, CallError>;
+
+    async fn install_code(
+        &self,
+        canister_id: Principal,
+        wasm_module: Vec<u8>,
+        arg: Vec<u8>,
+    ) -> Result<(), CallError>;
+
+    /// Current ICP/XDR conversion rate of the cycles minting canister.
+    async fn icp_xdr_conversion_rate(&self) -> Result<IcpXdrConversionRate, String>;
+}
+
+/// Runtime backed by the canister system API.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CdkRuntime;
+
+impl Runtime for CdkRuntime {
+    fn time(&self) -> u64 {
+        ic_cdk::api::time()
+    }
+
+    fn canister_id(&self) -> Principal {
+        ic_cdk::id()
+    }
+
+    async fn raw_rand(&self) -> Result<[u8; 32], String> {
+        let (random_bytes,) = ic_cdk::api::management_canister::main::raw_rand()
+            .await
+            .map_err(|(code, msg)| format!("Failed to generate random value ({code:?}): {msg}"))?;
+        random_bytes
+            .try_into()
+            .map_err(|_| "unexpected random value length".to_string())
+    }
+
+    fn cycles_burn(&self, amount: u128) -> u128 {
+        ic_cdk::api::cycles_burn(amount)
+    }
+
+    fn msg_cycles_available(&self) -> u128 {
+        ic_cdk::api::call::msg_cycles_available128()
+    }
+
+    fn msg_cycles_accept(&self, max_amount: u128) -> u128 {
+        ic_cdk::api::call::msg_cycles_accept128(max_amount)
+    }
+
+    async fn icrc1_transfer(
+        &self,
+        ledger_canister_id: Principal,
+        arg: TransferArg,
+    ) -> Result<u64, TransferError> {
+        let client = ICRC1Client {
+            runtime: icrc_ledger_client_cdk::CdkRuntime,
+            ledger_canister_id,
+        };
+        let block_index =
+            client
+                .transfer(arg)
+                .await
+                .map_err(|e| TransferError::GenericError {
+                    error_code: (Nat::from(e.0 as u32)),
+                    message: (e.1),
+                })??;
+        Ok(block_index.0.try_into().unwrap())
+    }
+
+    async fn icrc2_transfer_from(
+        &self,
+        ledger_canister_id: Principal,
+        args: TransferFromArgs,
+    ) -> Result<u64, TransferFromError> {
+        let result: Result<(Result<Nat, TransferFromError>,), _> =
+            ic_cdk::api::call::call(ledger_canister_id, "icrc2_transfer_from", (args,)).await;
+        let block_index = result
+            .map_err(|(code, msg)| TransferFromError::GenericError {
+                error_code: Nat::from(code as u32),
+                message: msg,
+            })?
+            .0?;
+        Ok(block_index.0.try_into().unwrap())
+    }
+
+    async fn fetch_icp_block(&self, block_index: u64) -> Result<icp_ledger::Block, PaymentError> {
+        crate::payment::fetch_block(block_index).await
+    }
+
+    async fn notify_top_up(&self, block_index: u64) -> Result<Cycles, String> {
+        crate::notify_top_up(block_index).await
+    }
+
+    async fn create_canister(
+        &self,
+        cycles_for_canister_creation: u64,
+    ) -> Result<Principal, CallError> {
+        crate::miner::create_canister(cycles_for_canister_creation).await
+    }
+
+    async fn install_code(
+        &self,
+        canister_id: Principal,
+        wasm_module: Vec<u8>,
+        arg: Vec<u8>,
+    ) -> Result<(), CallError> {
+        crate::miner::install_code(canister_id, wasm_module, arg).await
+    }
+
+    async fn icp_xdr_conversion_rate(&self) -> Result<IcpXdrConversionRate, String> {
+        crate::estimator::fetch_icp_xdr_conversion_rate().await
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use crate::miner::Reason;
+    use std::cell::{Cell, RefCell};
+    use std::collections::BTreeMap;
+
+    pub const CANISTER_ID: Principal = Principal::from_slice(&[0xca, 0xfe]);
+
+    /// In-memory runtime recording every call made by the minter logic.
+    #[derive(Default)]
+    pub struct MockRuntime {
+        pub time: Cell<u64>,
+        pub random: Cell<[u8; 32]>,
+        pub burned_cycles: Cell<u128>,
+        pub attached_cycles: Cell<u128>,
+        pub accepted_cycles: Cell<u128>,
+        pub fail_transfers: Cell<bool>,
+        pub fail_installs: Cell<bool>,
+        pub fail_notifications: Cell<bool>,
+        pub transfers: RefCell<Vec<(Principal, TransferArg)>>,
+        pub transfers_from: RefCell<Vec<(Principal, TransferFromArgs)>>,
+        pub icp_blocks: RefCell<BTreeMap<u64, icp_ledger::Block>>,
+        pub notified_blocks: RefCell<Vec<u64>>,
+        pub created_canisters: RefCell<Vec<Principal>>,
+        pub installed_canisters: RefCell<Vec<(Principal, Vec<u8>)>>,
+        pub xdr_permyriad_per_icp: Cell<u64>,
+    }
+
+    impl MockRuntime {
+        pub fn new(time: u64) -> Self {
+            let runtime = Self::default();
+            runtime.time.set(time);
+            runtime
+        }
+
+        pub fn transfers(&self) -> Vec<(Principal, TransferArg)> {
+            self.transfers.borrow().clone()
+        }
+    }
+
+    impl Runtime for MockRuntime {
+        fn time(&self) -> u64 {
+            self.time.get()
+        }
+
+        fn canister_id(&self) -> Principal {
+            CANISTER_ID
+        }
+
+        async fn raw_rand(&self) -> Result<[u8; 32], String> {
+            Ok(self.random.get())
+        }
+
+        fn cycles_burn(&self, amount: u128) -> u128 {
+            self.burned_cycles.set(self.burned_cycles.get() + amount);
+            amount
+        }
+
+        fn msg_cycles_available(&self) -> u128 {
+            self.attached_cycles.get() - self.accepted_cycles.get()
+        }
+
+        fn msg_cycles_accept(&self, max_amount: u128) -> u128 {
+            let accepted = max_amount.min(self.msg_cycles_available());
+            self.accepted_cycles
+                .set(self.accepted_cycles.get() + accepted);
+            accepted
+        }
+
+        async fn icrc1_transfer(
+            &self,
+            ledger_canister_id: Principal,
+            arg: TransferArg,
+        ) -> Result<u64, TransferError> {
+            if self.fail_transfers.get() {
+                return Err(TransferError::TemporarilyUnavailable);
+            }
+            let mut transfers = self.transfers.borrow_mut();
+            transfers.push((ledger_canister_id, arg));
+            Ok(transfers.len() as u64 - 1)
+        }
+
+        async fn icrc2_transfer_from(
+            &self,
+            ledger_canister_id: Principal,
+            args: TransferFromArgs,
+        ) -> Result<u64, TransferFromError> {
+            if self.fail_transfers.get() {
+                return Err(TransferFromError::TemporarilyUnavailable);
+            }
+            let mut transfers_from = self.transfers_from.borrow_mut();
+            transfers_from.push((ledger_canister_id, args));
+            Ok(transfers_from.len() as u64 - 1)
+        }
+
+        async fn fetch_icp_block(
+            &self,
+            block_index: u64,
+        ) -> Result<icp_ledger::Block, PaymentError> {
+            let blocks = self.icp_blocks.borrow();
+            blocks
+                .get(&block_index)
+                .cloned()
+                .ok_or(PaymentError::NotYetAvailable {
+                    chain_length: blocks.keys().last().map(|i| i + 1).unwrap_or(0),
+                })
+        }
+
+        async fn notify_top_up(&self, block_index: u64) -> Result<Cycles, String> {
+            if self.fail_notifications.get() {
+                return Err("the cycles minting canister is unavailable".to_string());
+            }
+            self.notified_blocks.borrow_mut().push(block_index);
+            Ok(Cycles::new(0))
+        }
+
+        async fn create_canister(
+            &self,
+            _cycles_for_canister_creation: u64,
+        ) -> Result<Principal, CallError> {
+            let mut created = self.created_canisters.borrow_mut();
+            let canister_id = Principal::from_slice(&(created.len() as u64).to_be_bytes());
+            created.push(canister_id);
+            Ok(canister_id)
+        }
+
+        async fn install_code(
+            &self,
+            canister_id: Principal,
+            wasm_module: Vec<u8>,
+            _arg: Vec<u8>,
+        ) -> Result<(), CallError> {
+            if self.fail_installs.get() {
+                return Err(CallError {
+                    method: "install_code".to_string(),
+                    reason: Reason::Rejected("out of memory".to_string()),
+                });
+            }
+            if !self.created_canisters.borrow().contains(&canister_id) {
+                return Err(CallError {
+                    method: "install_code".to_string(),
+                    reason: Reason::Rejected("canister not found".to_string()),
+                });
+            }
+            self.installed_canisters
+                .borrow_mut()
+                .push((canister_id, wasm_module));
+            Ok(())
+        }
+
+        async fn icp_xdr_conversion_rate(&self) -> Result<IcpXdrConversionRate, String> {
+            Ok(IcpXdrConversionRate {
+                timestamp_seconds: self.time.get() / crate::SEC_NANOS,
+                xdr_permyriad_per_icp: self.xdr_permyriad_per_icp.get(),
+            })
+        }
+    }
+}
@@ -0,0 +1,417 @@
+//! Export and import of the minter stable structures as a versioned CBOR
+//! snapshot, transferred in chunks to stay below the message size limits.
+//!
+//! The encoding is deterministic: stable maps are walked in key order, so a
+//! restored minter exports a snapshot with the same checksum as its source.
+
+use crate::locks::BobLock;
+use crate::memory::{
+    credit_claimable, enqueue_pool_payout, get_block_to_mine, get_bob_locks, get_claimables,
+    get_expire_map, get_extra_payouts, get_known_indices, get_mined_block,
+    get_miner_to_owner_and_index, get_pending_miner_installs, get_pool_payout_job,
+    get_pool_payouts, get_reward_streams, get_vesting_schedules, insert_block_index,
+    insert_block_to_mine, insert_bob_lock, insert_expiration, insert_extra_payout,
+    insert_new_miner, insert_pending_miner_install, insert_vesting_schedule, is_miner,
+    mined_block_count, miner_count, push_block, rebuild_mined_block_counters, set_pool_payout_job,
+    should_mine, update_reward_stream, user_count,
+};
+use crate::payment::INSTALL_RETRY_INTERVAL_SECS;
+use crate::payouts::PoolPayoutJob;
+use crate::streams::{ExtraPayout, RewardStream};
+use crate::tasks::{schedule_after, TaskType};
+use crate::vesting::VestingSchedule;
+use crate::Block;
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::time::Duration;
+
+/// Bumped on every change of the `Snapshot` layout, snapshots of another
+/// version are rejected.
+pub const SNAPSHOT_VERSION: u32 = 7;
+/// Chunks stay well below the 2MiB ingress and response limits.
+pub const SNAPSHOT_CHUNK_SIZE: u64 = 1_000_000;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub miner_to_owner: Vec<(Principal, (Principal, u64))>,
+    pub tx_log: Vec<Block>,
+    pub blocks_to_mine: Vec<Block>,
+    pub user_to_expiration: Vec<(Principal, u64)>,
+    pub known_index: Vec<u64>,
+    pub claimable: Vec<(Principal, u64)>,
+    pub bob_locks: Vec<(Principal, BobLock)>,
+    pub vesting_schedules: Vec<(Principal, VestingSchedule)>,
+    /// Payout of a pool block in progress, with the members left to pay.
+    pub pool_payout_job: Option<PoolPayoutJob>,
+    pub pool_payout_queue: Vec<Principal>,
+    /// Registered miners whose code is not installed yet, with their owner.
+    pub pending_miner_installs: Vec<(Principal, Principal)>,
+    pub reward_streams: Vec<(u64, RewardStream)>,
+    /// Secondary rewards owed, keyed by block timestamp, owner and ledger.
+    pub extra_payouts: Vec<((u64, Principal, Principal), ExtraPayout)>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub struct SnapshotInfo {
+    pub version: u32,
+    pub total_size: u64,
+    pub chunk_size: u64,
+    pub chunk_count: u64,
+    /// Hex encoded SHA-256 of the CBOR snapshot.
+    pub checksum: String,
+}
+
+struct PendingImport {
+    info: SnapshotInfo,
+    chunks: Vec<Option<Vec<u8>>>,
+}
+
+thread_local! {
+    static EXPORT: RefCell<Option<(SnapshotInfo, Vec<u8>)>> = RefCell::default();
+    static IMPORT: RefCell<Option<PendingImport>> = RefCell::default();
+}
+
+fn checksum(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+fn take_snapshot() -> Snapshot {
+    Snapshot {
+        version: SNAPSHOT_VERSION,
+        miner_to_owner: get_miner_to_owner_and_index(),
+        tx_log: get_mined_block(),
+        blocks_to_mine: get_block_to_mine(),
+        user_to_expiration: get_expire_map(),
+        known_index: get_known_indices(),
+        claimable: get_claimables(),
+        bob_locks: get_bob_locks(),
+        vesting_schedules: get_vesting_schedules(),
+        pool_payout_job: get_pool_payout_job(),
+        pool_payout_queue: get_pool_payouts(u64::MAX),
+        pending_miner_installs: get_pending_miner_installs(),
+        reward_streams: get_reward_streams(),
+        extra_payouts: get_extra_payouts(),
+    }
+}
+
+/// Encodes the stable structures and keeps the result in the heap so that
+/// it can be downloaded chunk by chunk.
+pub fn prepare_export() -> SnapshotInfo {
+    let mut bytes = vec![];
+    ciborium::ser::into_writer(&take_snapshot(), &mut bytes)
+        .expect("failed to encode the snapshot");
+    let info = SnapshotInfo {
+        version: SNAPSHOT_VERSION,
+        total_size: bytes.len() as u64,
+        chunk_size: SNAPSHOT_CHUNK_SIZE,
+        chunk_count: (bytes.len() as u64).div_ceil(SNAPSHOT_CHUNK_SIZE),
+        checksum: checksum(&bytes),
+    };
+    EXPORT.with(|e| *e.borrow_mut() = Some((info.clone(), bytes)));
+    info
+}
+
+pub fn get_export_chunk(index: u64) -> Result<Vec<u8>, String> {
+    EXPORT.with(|e| {
+        let export = e.borrow();
+        let (info, bytes) = export.as_ref().ok_or("no snapshot prepared")?;
+        if index >= info.chunk_count {
+            return Err(format!(
+                "chunk {index} out of range, the snapshot has {} chunks",
+                info.chunk_count
+            ));
+        }
+        let start = (index * SNAPSHOT_CHUNK_SIZE) as usize;
+        let end = (start + SNAPSHOT_CHUNK_SIZE as usize).min(bytes.len());
+        Ok(bytes[start..end].to_vec())
+    })
+}
+
+fn is_fresh_minter() -> bool {
+    // A fresh minter only knows about the pool.
+    mined_block_count() == 0 && !should_mine() && user_count() == 0 && miner_count() <= 1
+}
+
+pub fn begin_import(info: SnapshotInfo) -> Result<(), String> {
+    if info.version != SNAPSHOT_VERSION {
+        return Err(format!(
+            "unsupported snapshot version {}, expected {SNAPSHOT_VERSION}",
+            info.version
+        ));
+    }
+    if info.chunk_size == 0 || info.total_size.div_ceil(info.chunk_size) != info.chunk_count {
+        return Err("inconsistent snapshot size and chunk count".to_string());
+    }
+    if !is_fresh_minter() {
+        return Err("snapshots can only be imported into a fresh minter".to_string());
+    }
+    IMPORT.with(|i| {
+        *i.borrow_mut() = Some(PendingImport {
+            chunks: vec![None; info.chunk_count as usize],
+            info,
+        })
+    });
+    Ok(())
+}
+
+pub fn upload_chunk(index: u64, chunk: Vec<u8>) -> Result<(), String> {
+    IMPORT.with(|i| {
+        let mut import = i.borrow_mut();
+        let import = import.as_mut().ok_or("no snapshot import in progress")?;
+        if index >= import.info.chunk_count {
+            return Err(format!("chunk {index} out of range"));
+        }
+        let expected_len = if index + 1 == import.info.chunk_count {
+            import.info.total_size - index * import.info.chunk_size
+        } else {
+            import.info.chunk_size
+        };
+        if chunk.len() as u64 != expected_len {
+            return Err(format!(
+                "chunk {index} has {} bytes, expected {expected_len}",
+                chunk.len()
+            ));
+        }
+        import.chunks[index as usize] = Some(chunk);
+        Ok(())
+    })
+}
+
+/// Verifies the uploaded snapshot against its checksum and writes it into
+/// the stable structures.
+pub fn finish_import() -> Result<SnapshotInfo, String> {
+    let import = IMPORT
+        .with(|i| i.borrow_mut().take())
+        .ok_or("no snapshot import in progress")?;
+    let missing = import.chunks.iter().filter(|c| c.is_none()).count();
+    if missing > 0 {
+        IMPORT.with(|i| *i.borrow_mut() = Some(import));
+        return Err(format!("{missing} chunks are missing"));
+    }
+    let bytes: Vec<u8> = import.chunks.into_iter().flatten().flatten().collect();
+    if checksum(&bytes) != import.info.checksum {
+        return Err("checksum mismatch, the import was discarded".to_string());
+    }
+    let snapshot: Snapshot = ciborium::de::from_reader(bytes.as_slice())
+        .map_err(|e| format!("failed to decode the snapshot: {e}"))?;
+    if snapshot.version != SNAPSHOT_VERSION {
+        return Err(format!("unsupported snapshot version {}", snapshot.version));
+    }
+    if !is_fresh_minter() {
+        return Err("snapshots can only be imported into a fresh minter".to_string());
+    }
+
+    for (miner, (owner, block_index)) in snapshot.miner_to_owner {
+        if !is_miner(miner) {
+            insert_new_miner(miner, owner, block_index);
+        }
+    }
+    for block in snapshot.tx_log {
+        push_block(block);
+    }
+    for block in snapshot.blocks_to_mine {
+        insert_block_to_mine(block);
+    }
+    for (owner, expiration) in snapshot.user_to_expiration {
+        insert_expiration(owner, expiration);
+    }
+    for block_index in snapshot.known_index {
+        insert_block_index(block_index);
+    }
+    for (owner, amount) in snapshot.claimable {
+        credit_claimable(owner, amount);
+    }
+    for (owner, lock) in snapshot.bob_locks {
+        insert_bob_lock(owner, lock);
+    }
+    for (owner, schedule) in snapshot.vesting_schedules {
+        insert_vesting_schedule(owner, schedule);
+    }
+    set_pool_payout_job(snapshot.pool_payout_job);
+    for owner in snapshot.pool_payout_queue {
+        enqueue_pool_payout(owner);
+    }
+    if !snapshot.pending_miner_installs.is_empty() {
+        schedule_after(
+            Duration::from_secs(INSTALL_RETRY_INTERVAL_SECS),
+            TaskType::InstallMiners,
+        );
+    }
+    for (miner, owner) in snapshot.pending_miner_installs {
+        insert_pending_miner_install(miner, owner);
+    }
+    for (id, stream) in snapshot.reward_streams {
+        update_reward_stream(id, stream);
+    }
+    for ((block_timestamp, to, ledger_id), payout) in snapshot.extra_payouts {
+        insert_extra_payout(block_timestamp, to, ledger_id, payout);
+    }
+    rebuild_mined_block_counters();
+
+    Ok(import.info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{
+        get_bob_lock, get_claimable, get_expiration, get_miner_owner, get_reward_stream,
+        is_known_block, pool_payout_queue_len,
+    };
+
+    const MINER: Principal = Principal::from_slice(&[0x01]);
+    const OWNER: Principal = Principal::from_slice(&[0x02]);
+    const LEDGER: Principal = Principal::from_slice(&[0x03]);
+
+    fn export() -> (SnapshotInfo, Vec<Vec<u8>>) {
+        insert_new_miner(MINER, OWNER, 7);
+        push_block(Block {
+            to: OWNER,
+            miner: Some(MINER),
+            rewards: 60_000_000_000,
+            timestamp: 42,
+            total_cycles_burned: Some(1_000),
+            miner_cycles_burned: Some(1_000),
+            miner_count: Some(1),
+            extra_rewards: None,
+        });
+        insert_expiration(OWNER, 1_000);
+        insert_block_index(7);
+        credit_claimable(OWNER, 500);
+        insert_bob_lock(
+            OWNER,
+            BobLock {
+                amount_e8s: 100,
+                locked_at: 0,
+                unlock_at: 1_000,
+                multiplier_bps: 10_500,
+                ledger_index: 3,
+            },
+        );
+        insert_vesting_schedule(
+            OWNER,
+            VestingSchedule {
+                block_timestamp: 42,
+                total: 1_000,
+                released: 250,
+                start: 0,
+                end: 2_000,
+            },
+        );
+        set_pool_payout_job(Some(PoolPayoutJob {
+            block_timestamp: 43,
+            reward: 100,
+            started_at: 43,
+            member_count: 2,
+            processed: 1,
+            paid: 1,
+            credited: 0,
+            extra_rewards: vec![],
+        }));
+        enqueue_pool_payout(OWNER);
+        insert_pending_miner_install(MINER, OWNER);
+        update_reward_stream(
+            3,
+            RewardStream {
+                ledger_id: LEDGER,
+                amount_per_block: 10,
+                budget: 1_000,
+                spent: 40,
+                start_block: 0,
+                end_block: None,
+                registered_at: 0,
+            },
+        );
+        insert_extra_payout(
+            42,
+            OWNER,
+            LEDGER,
+            ExtraPayout {
+                amount: 10,
+                attempts: 2,
+                retry_at: 100,
+            },
+        );
+
+        let info = prepare_export();
+        let chunks = (0..info.chunk_count)
+            .map(|index| get_export_chunk(index).unwrap())
+            .collect();
+        (info, chunks)
+    }
+
+    #[test]
+    fn should_restore_a_snapshot_with_the_same_checksum() {
+        let (info, chunks) = export();
+        assert_eq!(info.chunk_count, 1);
+        assert!(get_export_chunk(info.chunk_count).is_err());
+
+        // The stable memory is thread local, a new thread is a fresh minter.
+        std::thread::spawn(move || {
+            begin_import(info.clone()).unwrap();
+            assert_eq!(finish_import(), Err("1 chunks are missing".to_string()));
+            for (index, chunk) in chunks.into_iter().enumerate() {
+                upload_chunk(index as u64, chunk).unwrap();
+            }
+            assert_eq!(finish_import(), Ok(info.clone()));
+
+            assert_eq!(get_miner_owner(MINER), Some(OWNER));
+            assert_eq!(get_mined_block().len(), 1);
+            assert_eq!(get_expiration(OWNER), Some(1_000));
+            assert!(is_known_block(7));
+            assert_eq!(get_claimable(OWNER), 500);
+            assert_eq!(get_bob_lock(OWNER).map(|lock| lock.ledger_index), Some(3));
+            assert_eq!(get_vesting_schedules().len(), 1);
+            assert_eq!(get_pool_payout_job().map(|job| job.processed), Some(1));
+            assert_eq!(pool_payout_queue_len(), 1);
+            assert_eq!(get_pending_miner_installs(), vec![(MINER, OWNER)]);
+            assert_eq!(get_reward_stream(3).map(|stream| stream.spent), Some(40));
+            assert_eq!(get_extra_payouts()[0].1.attempts, 2);
+            assert_eq!(prepare_export().checksum, info.checksum);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn should_reject_a_snapshot_of_another_version() {
+        let (info, _) = export();
+
+        std::thread::spawn(move || {
+            let older = SnapshotInfo {
+                version: SNAPSHOT_VERSION - 1,
+                ..info
+            };
+            assert_eq!(
+                begin_import(older),
+                Err(format!(
+                    "unsupported snapshot version {}, expected {SNAPSHOT_VERSION}",
+                    SNAPSHOT_VERSION - 1
+                ))
+            );
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn should_reject_a_corrupted_snapshot() {
+        let (info, mut chunks) = export();
+        chunks[0][0] ^= 0xff;
+
+        std::thread::spawn(move || {
+            begin_import(info).unwrap();
+            upload_chunk(0, chunks.remove(0)).unwrap();
+            assert_eq!(
+                finish_import(),
+                Err("checksum mismatch, the import was discarded".to_string())
+            );
+            assert_eq!(mined_block_count(), 0);
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,279 @@
+//! Secondary reward streams: partner projects fund the minter with their own
+//! ICRC-1 tokens, which are paid to the winner of every block, or split
+//! between the pool members, alongside the BOB rewards.
+//!
+//! The amount of each stream is reserved when a block is solved and recorded
+//! in the block. Once the BOB rewards of the block are paid, the share of
+//! every recipient is queued in stable memory and transferred by MineBob.
+//! Failed transfers are retried with an exponential backoff and dropped
+//! after `MAX_EXTRA_PAYOUT_ATTEMPTS`.
+
+use crate::events::{record_event, EventType};
+use crate::logs::INFO;
+use crate::memory::{
+    enqueue_extra_payout, get_extra_payouts, get_reward_streams, insert_extra_payout,
+    remove_extra_payout, update_reward_stream,
+};
+use crate::reconciliation::payout_memo;
+use crate::runtime::Runtime;
+use crate::{transfer, SEC_NANOS};
+use candid::{CandidType, Principal};
+use ic_canister_log::log;
+use icrc_ledger_types::icrc1::transfer::Memo;
+use serde::{Deserialize, Serialize};
+
+/// Maximum number of secondary reward transfers per run.
+const MAX_EXTRA_PAYOUTS_PER_RUN: usize = 50;
+/// A reward is dropped after this many failed transfers.
+pub const MAX_EXTRA_PAYOUT_ATTEMPTS: u32 = 10;
+/// Delay before the first retry, doubled after every failure.
+const EXTRA_PAYOUT_RETRY_DELAY_NANOS: u64 = 15 * SEC_NANOS;
+const MAX_EXTRA_PAYOUT_RETRY_DELAY_NANOS: u64 = 60 * 60 * SEC_NANOS;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub struct RewardStream {
+    pub ledger_id: Principal,
+    pub amount_per_block: u64,
+    pub budget: u64,
+    /// Amount reserved by the blocks solved so far.
+    pub spent: u64,
+    /// First block height, counting the blocks of the first minter, that
+    /// receives the stream.
+    pub start_block: u64,
+    /// Height at which the stream ends, excluded.
+    pub end_block: Option<u64>,
+    pub registered_at: u64,
+}
+
+impl RewardStream {
+    pub fn is_active(&self, height: u64) -> bool {
+        self.start_block <= height
+            && self.end_block.map_or(true, |end_block| height < end_block)
+            && self.budget.saturating_sub(self.spent) >= self.amount_per_block
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RewardStreamArgs {
+    pub ledger_id: Principal,
+    pub amount_per_block: u64,
+    pub budget: u64,
+    pub start_block: u64,
+    pub end_block: Option<u64>,
+}
+
+impl RewardStreamArgs {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.amount_per_block == 0 {
+            return Err("amount_per_block must be positive".to_string());
+        }
+        if self.budget < self.amount_per_block {
+            return Err("budget must cover at least one block".to_string());
+        }
+        if self
+            .end_block
+            .is_some_and(|end_block| end_block <= self.start_block)
+        {
+            return Err("end_block must be after start_block".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, CandidType)]
+pub struct ExtraReward {
+    pub ledger_id: Principal,
+    pub amount: u64,
+}
+
+/// A secondary reward waiting to be paid.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtraPayout {
+    pub amount: u64,
+    /// Failed transfers so far.
+    pub attempts: u32,
+    /// The transfer is not retried before this time.
+    pub retry_at: u64,
+}
+
+impl ExtraPayout {
+    pub fn new(amount: u64) -> Self {
+        Self {
+            amount,
+            attempts: 0,
+            retry_at: 0,
+        }
+    }
+}
+
+fn retry_delay(attempts: u32) -> u64 {
+    EXTRA_PAYOUT_RETRY_DELAY_NANOS
+        .saturating_mul(1_u64 << attempts.saturating_sub(1).min(32))
+        .min(MAX_EXTRA_PAYOUT_RETRY_DELAY_NANOS)
+}
+
+/// Reserves the amount of every active stream for the block at `height`.
+pub fn reserve_extra_rewards(height: u64) -> Vec<ExtraReward> {
+    let mut rewards = vec![];
+    for (id, mut stream) in get_reward_streams() {
+        if !stream.is_active(height) {
+            continue;
+        }
+        stream.spent += stream.amount_per_block;
+        rewards.push(ExtraReward {
+            ledger_id: stream.ledger_id,
+            amount: stream.amount_per_block,
+        });
+        update_reward_stream(id, stream);
+    }
+    rewards
+}
+
+/// Queues the secondary rewards of a block for one of its recipients, who
+/// receives one of `shares` equal parts of every reward.
+pub fn queue_extra_rewards(
+    block_timestamp: u64,
+    extra_rewards: &[ExtraReward],
+    to: Principal,
+    shares: u64,
+) {
+    for reward in extra_rewards {
+        let amount = reward.amount.checked_div(shares).unwrap_or(0);
+        if amount > 0 {
+            enqueue_extra_payout(block_timestamp, to, reward.ledger_id, amount);
+        }
+    }
+}
+
+/// Transfers the queued secondary rewards that are due. Failed transfers
+/// are retried later and dropped once they reach the maximum number of
+/// attempts. Returns the time of the next retry if some rewards are still
+/// waiting to be paid.
+pub async fn pay_extra_rewards<R: Runtime>(runtime: &R) -> Option<u64> {
+    let now = runtime.time();
+    let (due, waiting): (Vec<_>, Vec<_>) = get_extra_payouts()
+        .into_iter()
+        .partition(|(_, payout)| payout.retry_at <= now);
+    let mut next_retry_at = if due.len() > MAX_EXTRA_PAYOUTS_PER_RUN {
+        Some(now)
+    } else {
+        waiting.iter().map(|(_, payout)| payout.retry_at).min()
+    };
+
+    for ((block_timestamp, to, ledger_id), mut payout) in
+        due.into_iter().take(MAX_EXTRA_PAYOUTS_PER_RUN)
+    {
+        let amount = payout.amount;
+        // Removed before the transfer so that a reward is never paid twice.
+        remove_extra_payout(block_timestamp, to, ledger_id);
+        match transfer(
+            runtime,
+            to,
+            amount.into(),
+            None,
+            Some(Memo::from(payout_memo(block_timestamp))),
+            ledger_id,
+        )
+        .await
+        {
+            Ok(ledger_index) => log!(
+                INFO,
+                "[pay_extra_rewards] paid {amount} of {ledger_id} to {to} at ledger block {ledger_index}"
+            ),
+            Err(e) => {
+                payout.attempts += 1;
+                if payout.attempts >= MAX_EXTRA_PAYOUT_ATTEMPTS {
+                    log!(
+                        INFO,
+                        "[pay_extra_rewards] dropping {amount} of {ledger_id} to {to} after {} attempts: {e:?}",
+                        payout.attempts
+                    );
+                    record_event(
+                        EventType::ExtraRewardDropped {
+                            block_timestamp,
+                            to,
+                            ledger_id,
+                            amount,
+                        },
+                        now,
+                    );
+                    continue;
+                }
+                log!(
+                    INFO,
+                    "[pay_extra_rewards] failed to transfer {amount} of {ledger_id} to {to}: {e:?}"
+                );
+                payout.retry_at = now + retry_delay(payout.attempts);
+                next_retry_at = Some(
+                    next_retry_at.map_or(payout.retry_at, |next| next.min(payout.retry_at)),
+                );
+                insert_extra_payout(block_timestamp, to, ledger_id, payout);
+            }
+        }
+    }
+    next_retry_at
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{event_count, extra_payout_count, get_event};
+    use crate::runtime::mock::MockRuntime;
+    use futures::executor::block_on;
+
+    const OWNER: Principal = Principal::from_slice(&[0x02]);
+    const PARTNER_LEDGER: Principal = Principal::from_slice(&[0x10]);
+
+    #[test]
+    fn should_pay_queued_extra_rewards() {
+        let runtime = MockRuntime::new(0);
+        enqueue_extra_payout(42, OWNER, PARTNER_LEDGER, 300);
+        enqueue_extra_payout(42, OWNER, PARTNER_LEDGER, 200);
+
+        assert_eq!(block_on(pay_extra_rewards(&runtime)), None);
+        let transfers = runtime.transfers();
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].0, PARTNER_LEDGER);
+        assert_eq!(transfers[0].1.amount, candid::Nat::from(500_u64));
+        assert_eq!(extra_payout_count(), 0);
+    }
+
+    #[test]
+    fn should_back_off_and_drop_failing_extra_rewards() {
+        let runtime = MockRuntime::new(0);
+        runtime.fail_transfers.set(true);
+        enqueue_extra_payout(42, OWNER, PARTNER_LEDGER, 500);
+
+        assert_eq!(
+            block_on(pay_extra_rewards(&runtime)),
+            Some(EXTRA_PAYOUT_RETRY_DELAY_NANOS)
+        );
+        runtime.time.set(EXTRA_PAYOUT_RETRY_DELAY_NANOS - 1);
+        assert_eq!(
+            block_on(pay_extra_rewards(&runtime)),
+            Some(EXTRA_PAYOUT_RETRY_DELAY_NANOS)
+        );
+        assert_eq!(get_extra_payouts()[0].1.attempts, 1);
+
+        runtime.time.set(EXTRA_PAYOUT_RETRY_DELAY_NANOS);
+        assert_eq!(
+            block_on(pay_extra_rewards(&runtime)),
+            Some(3 * EXTRA_PAYOUT_RETRY_DELAY_NANOS)
+        );
+
+        for _ in 2..MAX_EXTRA_PAYOUT_ATTEMPTS {
+            runtime.time.set(get_extra_payouts()[0].1.retry_at);
+            block_on(pay_extra_rewards(&runtime));
+        }
+        assert_eq!(extra_payout_count(), 0);
+        assert_eq!(
+            get_event(event_count() - 1).unwrap().payload,
+            EventType::ExtraRewardDropped {
+                block_timestamp: 42,
+                to: OWNER,
+                ledger_id: PARTNER_LEDGER,
+                amount: 500,
+            }
+        );
+    }
+}
@@ -15,6 +15,11 @@ thread_local! {
 pub enum TaskType {
     ProcessLogic,
     MineBob,
+    Reconcile,
+    RenewMemberships,
+    CompletePayments,
+    ReleaseVested,
+    InstallMiners,
 }
 
 #[derive(Clone, Debug, Ord, PartialOrd, Eq, PartialEq, CandidType)]
@@ -111,7 +116,7 @@ impl TaskQueue {
 
 /// Schedules a task for execution after the given delay.
 pub fn schedule_after(delay: Duration, work: TaskType) {
-    let now_nanos = ic_cdk::api::time();
+    let now_nanos = crate::timestamp_nanos();
     let execute_at = now_nanos.saturating_add(delay.as_secs() * crate::SEC_NANOS);
 
     let execution_time = TASKS.with(|t| t.borrow_mut().schedule_at(execute_at, work));
@@ -125,7 +130,7 @@ pub fn schedule_now(work: TaskType) {
 
 /// Dequeues the next task ready for execution from the minter task queue.
 pub fn pop_if_ready() -> Option<Task> {
-    let now = ic_cdk::api::time();
+    let now = crate::timestamp_nanos();
     let task = TASKS.with(|t| t.borrow_mut().pop_if_ready(now));
     if let Some(next_execution) = TASKS.with(|t| t.borrow().next_execution_timestamp()) {
         set_global_timer(next_execution);
@@ -133,6 +138,11 @@ pub fn pop_if_ready() -> Option<Task> {
     task
 }
 
+/// Returns the time at which the given task is scheduled, if it is queued.
+pub fn get_deadline(task_type: TaskType) -> Option<u64> {
+    TASKS.with(|t| t.borrow().deadline_by_task.get(&task_type).cloned())
+}
+
 /// Returns the current value of the global task timer.
 pub fn global_timer() -> u64 {
     LAST_GLOBAL_TIMER.with(|v| v.get())
@@ -0,0 +1,227 @@
+//! Optional vesting of block rewards. When enabled by the controllers, a
+//! fraction of every reward is held by the minter in a schedule of the owner
+//! and released linearly over the vesting period, either by the
+//! ReleaseVested task or by the owner calling `claim_vested`.
+//!
+//! Releases are paid like claims: a single transfer per owner whose memo is
+//! the release time, recorded as a payout so that the reconciliation matches
+//! it.
+
+use crate::admin::Subsystem;
+use crate::events::{record_event, EventType};
+use crate::logs::INFO;
+use crate::memory::{
+    get_vesting_params, get_vesting_schedule, get_vesting_schedules_of, insert_vesting_schedule,
+    is_paused, record_payout, remove_vesting_schedule, vesting_owners,
+};
+use crate::reconciliation::{payout_memo, Payout};
+use crate::runtime::Runtime;
+use crate::tasks::{schedule_after, TaskType};
+use crate::{read_state, transfer, DAY_NANOS};
+use candid::{CandidType, Nat, Principal};
+use ic_canister_log::log;
+use icrc_ledger_types::icrc1::transfer::Memo;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+pub const BPS: u64 = 10_000;
+/// At least part of every reward is paid right away.
+pub const MAX_VESTED_BPS: u64 = 9_000;
+pub const MAX_VESTING_DAYS: u64 = 365;
+/// Maximum number of owners released per run of the task.
+const MAX_RELEASES_PER_RUN: usize = 20;
+pub const RELEASE_INTERVAL_SECS: u64 = 60 * 60;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub struct VestingParams {
+    /// Fraction of the block rewards that vests, in basis points. Vesting
+    /// is disabled when zero.
+    pub vested_bps: u64,
+    pub duration_days: u64,
+}
+
+impl Default for VestingParams {
+    fn default() -> Self {
+        Self {
+            vested_bps: 0,
+            duration_days: 30,
+        }
+    }
+}
+
+impl VestingParams {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.vested_bps > MAX_VESTED_BPS {
+            return Err(format!("at most {MAX_VESTED_BPS} bps of a reward can vest"));
+        }
+        if self.duration_days == 0 || self.duration_days > MAX_VESTING_DAYS {
+            return Err(format!(
+                "the vesting period must be between 1 and {MAX_VESTING_DAYS} days"
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, CandidType)]
+pub struct VestingSchedule {
+    pub block_timestamp: u64,
+    pub total: u64,
+    pub released: u64,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl VestingSchedule {
+    pub fn vested(&self, now: u64) -> u64 {
+        if now >= self.end {
+            return self.total;
+        }
+        let elapsed = now.saturating_sub(self.start) as u128;
+        (self.total as u128 * elapsed / (self.end - self.start) as u128) as u64
+    }
+
+    pub fn releasable(&self, now: u64) -> u64 {
+        self.vested(now).saturating_sub(self.released)
+    }
+}
+
+/// Splits a reward into the amount paid right away and the amount that
+/// vests, according to the current parameters.
+pub fn split_reward(amount: u64) -> (u64, u64) {
+    let vested = (amount as u128 * get_vesting_params().vested_bps as u128 / BPS as u128) as u64;
+    (amount - vested, vested)
+}
+
+/// Starts the vesting of `amount` for `owner`, once the part of the reward
+/// paid right away has been paid.
+pub fn vest_reward(block_timestamp: u64, owner: Principal, amount: u64, now: u64) {
+    if amount == 0 {
+        return;
+    }
+    let duration = get_vesting_params().duration_days * DAY_NANOS;
+    let schedule = match get_vesting_schedule(owner, block_timestamp) {
+        Some(mut schedule) => {
+            schedule.total += amount;
+            schedule
+        }
+        None => VestingSchedule {
+            block_timestamp,
+            total: amount,
+            released: 0,
+            start: now,
+            end: now + duration,
+        },
+    };
+    insert_vesting_schedule(owner, schedule);
+    schedule_after(
+        Duration::from_secs(RELEASE_INTERVAL_SECS),
+        TaskType::ReleaseVested,
+    );
+    record_event(
+        EventType::RewardVested {
+            block_timestamp,
+            to: owner,
+            amount,
+        },
+        now,
+    );
+}
+
+pub fn releasable(owner: Principal, now: u64) -> u64 {
+    get_vesting_schedules_of(owner)
+        .iter()
+        .map(|schedule| schedule.releasable(now))
+        .sum()
+}
+
+/// Transfers the vested rewards of `owner` in a single mint. The schedules
+/// are updated before the transfer and restored if it fails.
+pub async fn release_vested<R: Runtime>(runtime: &R, owner: Principal) -> Result<u64, String> {
+    if is_paused(Subsystem::Payouts) {
+        return Err("payouts are paused".to_string());
+    }
+
+    let now = runtime.time();
+    let schedules = get_vesting_schedules_of(owner);
+    let amount: u64 = schedules.iter().map(|s| s.releasable(now)).sum();
+    if amount == 0 {
+        return Err("nothing to release".to_string());
+    }
+    for schedule in schedules.iter() {
+        let mut schedule = schedule.clone();
+        schedule.released = schedule.vested(now);
+        if schedule.released == schedule.total {
+            remove_vesting_schedule(owner, schedule.block_timestamp);
+        } else {
+            insert_vesting_schedule(owner, schedule);
+        }
+    }
+
+    let ledger_canister_id = read_state(|s| s.bob_ledger_id);
+    match transfer(
+        runtime,
+        owner,
+        amount.into(),
+        Some(Nat::from(0_u8)),
+        Some(Memo::from(payout_memo(now))),
+        ledger_canister_id,
+    )
+    .await
+    {
+        Ok(ledger_index) => {
+            record_payout(
+                ledger_index,
+                Payout {
+                    block_timestamp: now,
+                    to: owner,
+                    amount,
+                },
+            );
+            record_event(
+                EventType::VestedReleased {
+                    owner,
+                    amount,
+                    ledger_index,
+                },
+                now,
+            );
+            log!(
+                INFO,
+                "[release_vested] released {amount} to {owner} at ledger block {ledger_index}"
+            );
+            Ok(amount)
+        }
+        Err(e) => {
+            for schedule in schedules {
+                insert_vesting_schedule(owner, schedule);
+            }
+            Err(format!("failed to transfer {amount}: {e:?}"))
+        }
+    }
+}
+
+/// Releases the vested rewards of the owners with a releasable amount.
+/// Returns true if some schedules are still vesting.
+pub async fn release_all_vested<R: Runtime>(runtime: &R) -> bool {
+    let now = runtime.time();
+    let due: Vec<Principal> = vesting_owners()
+        .into_iter()
+        .filter(|owner| releasable(*owner, now) > 0)
+        .take(MAX_RELEASES_PER_RUN)
+        .collect();
+    for owner in due {
+        // Skip owners claiming at the same time.
+        let _guard = match crate::guard::GuardPrincipal::new(owner) {
+            Ok(guard) => guard,
+            Err(_) => continue,
+        };
+        if let Err(e) = release_vested(runtime, owner).await {
+            log!(
+                INFO,
+                "[release_all_vested] failed to release the rewards of {owner}: {e}"
+            );
+        }
+    }
+    !vesting_owners().is_empty()
+}